@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use sha2::Digest;
+
+/// Fingerprint of a source line, used to find the line again after the source is edited.
+///
+/// `line` is the hash of the line itself and `context` is the hash of the line together with
+/// its neighbours, so identical lines (`}`, blank lines, ...) can still be told apart.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Anchor {
+    pub line: u32,
+    pub context: u32,
+}
+
+fn hash_lines(lines: &[&str]) -> u32 {
+    let mut hasher = sha2::Sha256::new();
+    for line in lines {
+        hasher.update(line.trim().as_bytes());
+        hasher.update(b"\n");
+    }
+    let result = hasher.finalize();
+    u32::from_be_bytes([result[0], result[1], result[2], result[3]])
+}
+
+impl Anchor {
+    pub fn at(source_lines: &[String], line_offset: usize) -> Option<Self> {
+        let line = source_lines.get(line_offset)?;
+        let prev = match line_offset {
+            0 => "",
+            _ => &source_lines[line_offset - 1],
+        };
+        let next = source_lines.get(line_offset + 1).map_or("", |s| s.as_str());
+        Some(Self {
+            line: hash_lines(&[line]),
+            context: hash_lines(&[prev, line, next]),
+        })
+    }
+}
+
+impl fmt::Display for Anchor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}.{:08x}", self.line, self.context)
+    }
+}
+
+impl FromStr for Anchor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (line, context) = s
+            .split_once('.')
+            .ok_or(anyhow::anyhow!("invalid anchor: {}", s))?;
+        Ok(Self {
+            line: u32::from_str_radix(line, 16)?,
+            context: u32::from_str_radix(context, 16)?,
+        })
+    }
+}
+
+/// Anchors of every line of a source, indexed by line hash.
+pub struct AnchorIndex {
+    anchors: Vec<Anchor>,
+    by_line: HashMap<u32, Vec<usize>>,
+}
+
+impl AnchorIndex {
+    pub fn new(source_lines: &[String]) -> Self {
+        let anchors: Vec<Anchor> = (0..source_lines.len())
+            .filter_map(|offset| Anchor::at(source_lines, offset))
+            .collect();
+        let mut by_line: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (offset, anchor) in anchors.iter().enumerate() {
+            by_line.entry(anchor.line).or_default().push(offset);
+        }
+        Self { anchors, by_line }
+    }
+
+    /// Find the current offset of the line that was at `hint` when `anchor` was taken.
+    ///
+    /// Lines matching both the line and its context win over lines matching only the line
+    /// itself; ties are broken by the distance from `hint`.
+    pub fn resolve(&self, anchor: &Anchor, hint: usize) -> Option<usize> {
+        if self.anchors.get(hint) == Some(anchor) {
+            return Some(hint);
+        }
+        let candidates = self.by_line.get(&anchor.line)?;
+        candidates
+            .iter()
+            .copied()
+            .min_by_key(|&offset| (self.anchors[offset] != *anchor, offset.abs_diff(hint)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn resolve_line_moved_by_insertions() {
+        let old = lines(&["a", "b", "c"]);
+        let anchor = Anchor::at(&old, 1).unwrap();
+        let new = lines(&["x", "y", "a", "b", "c"]);
+        assert_eq!(AnchorIndex::new(&new).resolve(&anchor, 1), Some(3));
+    }
+
+    #[test]
+    fn resolve_duplicate_lines_by_context() {
+        let old = lines(&["a", "}", "b", "}", "c"]);
+        let anchor = Anchor::at(&old, 3).unwrap();
+        // the first "}" is closer to the hint, but only the second one has the same neighbours
+        let new = lines(&["x", "x", "x", "a", "}", "b", "}", "c"]);
+        assert_eq!(AnchorIndex::new(&new).resolve(&anchor, 3), Some(6));
+    }
+
+    #[test]
+    fn resolve_removed_line() {
+        let old = lines(&["a", "b", "c"]);
+        let anchor = Anchor::at(&old, 1).unwrap();
+        let new = lines(&["a", "c"]);
+        assert_eq!(AnchorIndex::new(&new).resolve(&anchor, 1), None);
+    }
+
+    #[test]
+    fn anchor_round_trips_through_text() {
+        let anchor = Anchor::at(&lines(&["fn main() {}"]), 0).unwrap();
+        assert_eq!(anchor.to_string().parse::<Anchor>().unwrap(), anchor);
+    }
+}
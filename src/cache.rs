@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use crate::SourceFile;
+
+/// Line counts of sources, reused while the size and modification time of a source stay the
+/// same. Counting lines is most of the work of status for sources without marks.
+#[derive(Debug, Default)]
+pub struct LineCountCache {
+    // None for a cache which is not saved
+    path: Option<PathBuf>,
+    // absolute path of source -> entry
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    size: u64,
+    mtime: u128,
+    line_no: u32,
+}
+
+impl LineCountCache {
+    /// Cache saved to `path`. A missing or broken cache file gives an empty cache.
+    pub fn open<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = HashMap::new();
+        // "<size>\t<mtime>\t<line count>\t<path>"
+        for line in fs::read_to_string(&path).unwrap_or_default().lines() {
+            let mut fields = line.splitn(4, '\t');
+            let (Some(size), Some(mtime), Some(line_no), Some(source_path)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let (Ok(size), Ok(mtime), Ok(line_no)) = (size.parse(), mtime.parse(), line_no.parse())
+            else {
+                continue;
+            };
+            let entry = Entry {
+                size,
+                mtime,
+                line_no,
+            };
+            entries.insert(PathBuf::from(source_path), entry);
+        }
+
+        Self {
+            path: Some(path),
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Number of lines of a source, counted the same as `SourceFile::read`.
+    pub fn line_count<P: AsRef<Path>>(&self, source_path: P) -> anyhow::Result<u32> {
+        let source_path = std::path::absolute(source_path)?;
+        let metadata = fs::metadata(&source_path)?;
+        let size = metadata.len();
+        let mtime = metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+
+        let mut entries = self.entries.lock().expect("not poisoned");
+        if let Some(entry) = entries.get(&source_path) {
+            if entry.size == size && entry.mtime == mtime {
+                return Ok(entry.line_no);
+            }
+        }
+        drop(entries);
+
+        let line_no = SourceFile::read(&source_path)?.lines.len() as u32;
+        let entry = Entry {
+            size,
+            mtime,
+            line_no,
+        };
+        entries = self.entries.lock().expect("not poisoned");
+        entries.insert(source_path, entry);
+        Ok(line_no)
+    }
+
+    /// Write the cache back to its file, dropping sources which do not exist anymore.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let dir = path
+            .parent()
+            .ok_or(anyhow::anyhow!("failed to get parent directory"))?;
+        fs::create_dir_all(dir)?;
+
+        let mut entries = self.entries.lock().expect("not poisoned");
+        entries.retain(|source_path, _| source_path.exists());
+        let mut buf = String::new();
+        for (source_path, entry) in entries.iter() {
+            let Some(source_path) = source_path.to_str().filter(|p| !p.contains('\n')) else {
+                continue;
+            };
+            buf.push_str(&format!(
+                "{}\t{}\t{}\t{}\n",
+                entry.size, entry.mtime, entry.line_no, source_path
+            ));
+        }
+
+        let tmp = tempfile::NamedTempFile::new_in(dir)?;
+        fs::write(tmp.path(), buf)?;
+        tmp.persist(path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Kind of a mark. A line belongs to at most one category.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Category {
+    #[default]
+    Read,
+    FollowUp,
+    Suspicious,
+    Skip,
+}
+
+impl Category {
+    pub const ALL: [Category; 4] = [
+        Category::Read,
+        Category::FollowUp,
+        Category::Suspicious,
+        Category::Skip,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Read => "read",
+            Category::FollowUp => "needs-follow-up",
+            Category::Suspicious => "suspicious",
+            Category::Skip => "skip",
+        }
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Category {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Category::ALL
+            .into_iter()
+            .find(|category| category.name() == s)
+            .ok_or(anyhow::anyhow!("unknown category: {}", s))
+    }
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// Error of a line of a spec file.
+#[derive(Debug, thiserror::Error)]
+#[error("{}:{}: {}: {:?}", .path.display(), .line_no, .kind, .text)]
+pub struct ParseError {
+    pub path: PathBuf,
+    /// 1-based
+    pub line_no: usize,
+    pub text: String,
+    pub kind: ParseErrorKind,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseErrorKind {
+    #[error("invalid spec, expected `N`, `A-B`, `A..B`, `A..=B` or `/pattern/+N`")]
+    InvalidSpec,
+    #[error("line numbers start at 1")]
+    ZeroLine,
+    #[error("invalid anchor `{0}`")]
+    InvalidAnchor(String),
+    #[error("unknown category `{0}`")]
+    UnknownCategory(String),
+    #[error("invalid pattern `{0}`")]
+    InvalidPattern(String),
+    #[error("invalid note, expected `<spec>: <text>`")]
+    InvalidNote,
+    #[error("invalid value of `{0}`")]
+    InvalidMeta(String),
+    #[error(
+        "spec format {0} is newer than supported format {}",
+        crate::SPEC_FORMAT_VERSION
+    )]
+    UnsupportedFormat(u32),
+}
+
+/// Suspicious line of a spec file which is still accepted.
+#[derive(Debug)]
+pub struct ParseWarning {
+    pub path: PathBuf,
+    /// 1-based
+    pub line_no: usize,
+    pub text: String,
+    pub kind: ParseWarningKind,
+}
+
+#[derive(Debug)]
+pub enum ParseWarningKind {
+    /// `N-M` with N >= M, which marks nothing as M is exclusive
+    EmptyRange,
+    /// spec past the end of a source of that many lines
+    BeyondSource(usize),
+    /// `/pattern/` matching no line, or parsed without source
+    PatternNotFound,
+}
+
+impl fmt::Display for ParseWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: ", self.path.display(), self.line_no)?;
+        match self.kind {
+            ParseWarningKind::EmptyRange => write!(f, "range marks nothing")?,
+            ParseWarningKind::PatternNotFound => write!(f, "pattern matches no line")?,
+            ParseWarningKind::BeyondSource(line_count) => {
+                write!(f, "beyond the end of the source ({} lines)", line_count)?
+            }
+        }
+        write!(f, ": {:?}", self.text)
+    }
+}
@@ -0,0 +1,83 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{split_lines, SourceFile};
+
+fn git<P: AsRef<Path>>(source_path: P, args: &[&str]) -> Option<String> {
+    let source_path = source_path.as_ref();
+    let dir = source_path.parent().filter(|p| !p.as_os_str().is_empty());
+    git_in(dir, args)
+}
+
+fn git_in(dir: Option<&Path>, args: &[&str]) -> Option<String> {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+    let output = command.args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+/// Blob id of the source as it is in the working tree, if the source is inside a git repository.
+pub fn blob_id<P: AsRef<Path>>(source_path: P) -> Option<String> {
+    let source_path = source_path.as_ref();
+    git(source_path, &["rev-parse", "--git-dir"])?;
+
+    let file_name = source_path.file_name()?.to_str()?;
+    let blob = git(source_path, &["hash-object", "--", file_name])?;
+    Some(blob.trim().to_string())
+}
+
+/// Map every line offset of the source as it was at `blob` to its offset in the current source.
+///
+/// Lines removed or rewritten since then map to `None`. Returns `None` when the blob is not
+/// in the repository anymore, e.g. it was never committed.
+pub fn line_map(source: &SourceFile, blob: &str) -> Option<Vec<Option<usize>>> {
+    let recorded = git(&source.path, &["cat-file", "blob", blob])?;
+    let (recorded, _) = split_lines(&recorded);
+
+    // line endings are not compared, so converting them does not drop marks
+    let mut map = vec![None; recorded.len()];
+    let current: Vec<&str> = source.lines.iter().map(String::as_str).collect();
+    let ops = similar::capture_diff_slices(similar::Algorithm::Myers, &recorded, &current);
+    for op in ops {
+        if let similar::DiffOp::Equal {
+            old_index,
+            new_index,
+            len,
+        } = op
+        {
+            for i in 0..len {
+                if let Some(slot) = map.get_mut(old_index + i) {
+                    *slot = Some(new_index + i);
+                }
+            }
+        }
+    }
+    Some(map)
+}
+
+/// Files renamed between `rev` and the working tree of the repository containing `dir`, as
+/// absolute `(old, new)` paths. Renames have to be staged to be detected, e.g. by `git mv`.
+pub fn renames<P: AsRef<Path>>(dir: P, rev: &str) -> Option<Vec<(PathBuf, PathBuf)>> {
+    let dir = Some(dir.as_ref());
+    let root = git_in(dir, &["rev-parse", "--show-toplevel"])?;
+    let root = Path::new(root.trim());
+
+    let diff = git_in(
+        dir,
+        &["diff", "-M", "--name-status", "-z", "--diff-filter=R", rev],
+    )?;
+    // R<score> NUL <old> NUL <new> NUL
+    let mut fields = diff.split('\0');
+    let mut renames = vec![];
+    while let (Some(status), Some(old), Some(new)) = (fields.next(), fields.next(), fields.next()) {
+        if status.starts_with('R') {
+            renames.push((root.join(old), root.join(new)));
+        }
+    }
+    Some(renames)
+}
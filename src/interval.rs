@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Set of line offsets stored as sorted, disjoint and non-adjacent `[l, r)` ranges.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IntervalSet {
+    // l -> r
+    ranges: BTreeMap<u32, u32>,
+}
+
+impl IntervalSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn contains(&self, offset: u32) -> bool {
+        self.ranges
+            .range(..=offset)
+            .next_back()
+            .is_some_and(|(_, &r)| offset < r)
+    }
+
+    pub fn insert(&mut self, offset: u32) {
+        self.insert_range(offset..offset.saturating_add(1));
+    }
+
+    pub fn insert_range(&mut self, range: Range<u32>) {
+        if range.is_empty() {
+            return;
+        }
+        let (mut l, mut r) = (range.start, range.end);
+
+        // merge with the range starting before `l` if it overlaps or touches
+        if let Some((&prev_l, &prev_r)) = self.ranges.range(..=l).next_back() {
+            if prev_r >= l {
+                l = prev_l;
+                r = r.max(prev_r);
+            }
+        }
+        // swallow every range starting inside [l, r]
+        let overlapped: Vec<(u32, u32)> = self.ranges.range(l..=r).map(|(&l, &r)| (l, r)).collect();
+        for (next_l, next_r) in overlapped {
+            self.ranges.remove(&next_l);
+            r = r.max(next_r);
+        }
+        self.ranges.insert(l, r);
+    }
+
+    pub fn remove(&mut self, offset: u32) {
+        self.remove_range(offset..offset.saturating_add(1));
+    }
+
+    pub fn remove_range(&mut self, range: Range<u32>) {
+        if range.is_empty() {
+            return;
+        }
+        let (l, r) = (range.start, range.end);
+
+        // cut the range starting before `l`
+        if let Some((&prev_l, &prev_r)) = self.ranges.range(..l).next_back() {
+            if prev_r > l {
+                self.ranges.insert(prev_l, l);
+                if prev_r > r {
+                    self.ranges.insert(r, prev_r);
+                }
+            }
+        }
+        let overlapped: Vec<(u32, u32)> = self.ranges.range(l..r).map(|(&l, &r)| (l, r)).collect();
+        for (next_l, next_r) in overlapped {
+            self.ranges.remove(&next_l);
+            if next_r > r {
+                self.ranges.insert(r, next_r);
+            }
+        }
+    }
+
+    /// Number of offsets of the set inside `range`.
+    pub fn count_in(&self, range: Range<u32>) -> u32 {
+        self.iter()
+            .map(|(l, r)| r.min(range.end).saturating_sub(l.max(range.start)))
+            .sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.ranges.iter().map(|(&l, &r)| (l, r))
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        let mut set = self.clone();
+        for (l, r) in other.iter() {
+            set.insert_range(l..r);
+        }
+        set
+    }
+
+    pub fn intersection(&self, other: &Self) -> Self {
+        let mut set = Self::new();
+        let mut lhs = self.iter().peekable();
+        let mut rhs = other.iter().peekable();
+        while let (Some(&(a_l, a_r)), Some(&(b_l, b_r))) = (lhs.peek(), rhs.peek()) {
+            set.insert_range(a_l.max(b_l)..a_r.min(b_r));
+            if a_r < b_r {
+                lhs.next();
+            } else {
+                rhs.next();
+            }
+        }
+        set
+    }
+
+    pub fn difference(&self, other: &Self) -> Self {
+        let mut set = self.clone();
+        for (l, r) in other.iter() {
+            set.remove_range(l..r);
+        }
+        set
+    }
+
+    /// Offsets inside `range` which are not in the set.
+    pub fn complement(&self, range: Range<u32>) -> Self {
+        let mut set = Self::new();
+        set.insert_range(range);
+        set.difference(self)
+    }
+}
+
+impl FromIterator<Range<u32>> for IntervalSet {
+    fn from_iter<T: IntoIterator<Item = Range<u32>>>(iter: T) -> Self {
+        let mut set = Self::new();
+        for range in iter {
+            set.insert_range(range);
+        }
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(set: &IntervalSet) -> Vec<(u32, u32)> {
+        set.iter().collect()
+    }
+
+    #[test]
+    fn insert_merges_adjacent_and_overlapping_ranges() {
+        let mut set: IntervalSet = [0..2, 5..7].into_iter().collect();
+        set.insert_range(2..5);
+        assert_eq!(ranges(&set), vec![(0, 7)]);
+
+        set.insert_range(9..10);
+        set.insert(7);
+        assert_eq!(ranges(&set), vec![(0, 8), (9, 10)]);
+        set.insert_range(3..12);
+        assert_eq!(ranges(&set), vec![(0, 12)]);
+    }
+
+    #[test]
+    fn remove_splits_range() {
+        let mut set = IntervalSet::new();
+        set.insert_range(0..10);
+        set.remove_range(3..5);
+        assert_eq!(ranges(&set), vec![(0, 3), (5, 10)]);
+        set.remove(0);
+        set.remove(9);
+        assert_eq!(ranges(&set), vec![(1, 3), (5, 9)]);
+        set.remove_range(2..6);
+        assert_eq!(ranges(&set), vec![(1, 2), (6, 9)]);
+    }
+
+    #[test]
+    fn ranges_ending_at_max() {
+        let mut set = IntervalSet::new();
+        set.insert_range(10..u32::MAX);
+        assert!(set.contains(u32::MAX - 1));
+        assert!(!set.contains(u32::MAX));
+        set.insert(u32::MAX - 1);
+        assert_eq!(ranges(&set), vec![(10, u32::MAX)]);
+
+        set.remove_range(20..u32::MAX);
+        assert_eq!(ranges(&set), vec![(10, 20)]);
+        assert_eq!(
+            set.complement(0..u32::MAX),
+            [0..10, 20..u32::MAX].into_iter().collect()
+        );
+        assert_eq!(set.count_in(0..15), 5);
+    }
+
+    #[test]
+    fn intersection_with_equal_ends() {
+        let lhs: IntervalSet = [0..5, 8..10].into_iter().collect();
+        let rhs: IntervalSet = [3..5, 6..10].into_iter().collect();
+        assert_eq!(ranges(&lhs.intersection(&rhs)), vec![(3, 5), (8, 10)]);
+        assert_eq!(lhs.intersection(&lhs), lhs);
+        assert!(lhs.intersection(&IntervalSet::new()).is_empty());
+    }
+}
@@ -1,10 +1,29 @@
 use once_cell::sync::Lazy;
+use rayon::prelude::*;
 use regex::Regex;
 use sha2::Digest;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::{env, fs};
+use std::sync::Mutex;
+use std::{env, fmt, fs};
+
+mod anchor;
+mod cache;
+mod category;
+mod error;
+mod git;
+mod interval;
+
+pub use anchor::{Anchor, AnchorIndex};
+pub use cache::LineCountCache;
+pub use category::Category;
+pub use error::{ParseError, ParseErrorKind, ParseWarning, ParseWarningKind};
+pub use git::renames as git_renames;
+pub use interval::IntervalSet;
 
 pub fn get_spec_file_dir() -> PathBuf {
     let home = env::var("HOME").expect("failed to get $HOME env");
@@ -12,8 +31,201 @@ pub fn get_spec_file_dir() -> PathBuf {
     PathBuf::from(data_home).join("marks")
 }
 
+/// Path of the cache of line counts, see `LineCountCache`.
+pub fn get_line_count_cache_path() -> PathBuf {
+    let home = env::var("HOME").expect("failed to get $HOME env");
+    let cache_home = env::var("XDG_CACHE_HOME").unwrap_or_else(|_| format!("{}/.cache", home));
+    PathBuf::from(cache_home).join("marks").join("line-counts")
+}
+
+/// Directory name of project-local stores.
+pub const PROJECT_STORE_DIR: &str = ".marks";
+
+/// Find the project-local store of a source, i.e. the nearest `.marks` directory above it.
+pub fn find_project_store<P: AsRef<Path>>(file_path: P) -> Option<PathBuf> {
+    file_path
+        .as_ref()
+        .ancestors()
+        .skip(1)
+        .map(|dir| dir.join(PROJECT_STORE_DIR))
+        .find(|store| store.is_dir())
+}
+
+const SPEC_FILE_EXTENSION: &str = ".spec";
+
+/// Canonical path of a source which may not exist anymore, e.g. the old path of a renamed
+/// source: the nearest existing ancestor is canonicalized and the rest is appended as-is.
+pub fn canonical_path<P: AsRef<Path>>(file_path: P) -> anyhow::Result<PathBuf> {
+    let file_path = file_path.as_ref();
+    if let Ok(path) = fs::canonicalize(file_path) {
+        return Ok(path);
+    }
+    let file_path = env::current_dir()?.join(file_path);
+    for dir in file_path.ancestors().skip(1) {
+        if let Ok(path) = fs::canonicalize(dir) {
+            return Ok(path.join(file_path.strip_prefix(dir)?));
+        }
+    }
+    Ok(file_path)
+}
+
+// project-local store a spec file is in
+fn project_store_of_spec(spec_file_path: &Path) -> Option<&Path> {
+    spec_file_path.ancestors().find(|dir| {
+        dir.file_name()
+            .is_some_and(|name| name == PROJECT_STORE_DIR)
+    })
+}
+
+// project-local store of a canonical source path, and the path of the source relative to the
+// project
+fn project_relative_path(file_path: &Path) -> Option<(PathBuf, PathBuf)> {
+    let store = find_project_store(file_path)?;
+    let root = store.parent()?;
+    let relative = file_path.strip_prefix(root).ok()?.to_path_buf();
+    Some((store, relative))
+}
+
+/// Path of the source as recorded in its spec file: relative to the project for project-local
+/// stores, so shared spec files do not depend on where the project is checked out, and
+/// absolute otherwise.
+fn recorded_source_path<P: AsRef<Path>>(file_path: P) -> anyhow::Result<PathBuf> {
+    let file_path = canonical_path(file_path)?;
+    match project_relative_path(&file_path) {
+        Some((_, relative)) => Ok(relative),
+        None => Ok(file_path),
+    }
+}
+
+/// Source of a spec file, derived from the path of the spec file in a project-local store or
+/// as recorded in the spec file otherwise. Spec files written by older versions do not record it.
+pub fn source_path_of_spec<P: AsRef<Path>>(spec_file_path: P) -> anyhow::Result<Option<PathBuf>> {
+    let spec_file_path = spec_file_path.as_ref();
+    if let Some(store) = project_store_of_spec(spec_file_path) {
+        let relative = spec_file_path.strip_prefix(store)?.to_string_lossy();
+        let relative = relative
+            .strip_suffix(SPEC_FILE_EXTENSION)
+            .ok_or_else(|| anyhow::anyhow!("not a spec file"))?;
+        let root = store.parent().unwrap_or(Path::new(""));
+        return Ok(Some(root.join(relative)));
+    }
+
+    let marks = parse_spec_file(spec_file_path, None)?;
+    Ok(marks.source_path().map(Path::to_path_buf))
+}
+
+// file of a project-local store which is not a spec file, e.g. a README shared with the project
+fn is_foreign_file(path: &Path) -> bool {
+    project_store_of_spec(path).is_some() && !path.to_string_lossy().ends_with(SPEC_FILE_EXTENSION)
+}
+
+// file written by `tempfile` next to spec files before it replaces one, or kept after a failed
+// edit
+fn is_temporary_file(file_name: &OsStr) -> bool {
+    file_name.to_string_lossy().starts_with(".tmp")
+}
+
+/// Spec files in a store, e.g. `get_spec_file_dir()` or a project-local store.
+pub fn list_spec_files<P: AsRef<Path>>(store: P) -> anyhow::Result<Vec<PathBuf>> {
+    let mut spec_files = vec![];
+    for entry in fs::read_dir(store)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.is_dir() {
+            spec_files.extend(list_spec_files(&path)?);
+        } else if !is_temporary_file(&entry.file_name()) && !is_foreign_file(&path) {
+            spec_files.push(path);
+        }
+    }
+    spec_files.sort();
+    Ok(spec_files)
+}
+
+/// Remove a spec file, and the directories of the project-local store left empty by it. Other
+/// files of a project-local store are shared with the project and never removed.
+pub fn remove_spec_file<P: AsRef<Path>>(spec_file_path: P) -> anyhow::Result<()> {
+    let spec_file_path = spec_file_path.as_ref();
+    if is_foreign_file(spec_file_path) {
+        anyhow::bail!("{}: not a spec file", spec_file_path.display());
+    }
+    fs::remove_file(spec_file_path)?;
+    if let Some(store) = project_store_of_spec(spec_file_path) {
+        for dir in spec_file_path.ancestors().skip(1) {
+            if dir == store || fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Carry the marks of a source over to another source, e.g. after the source was renamed. The
+/// marks are relocated to the content of the other source, which must exist.
+pub fn copy_marks<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> anyhow::Result<()> {
+    let from_spec_file_path = get_spec_file_path(from);
+    let to_spec_file_path = get_spec_file_path(&to);
+    if !from_spec_file_path.is_file() {
+        return Err(anyhow::anyhow!("no marks found"));
+    }
+
+    let source = SourceFile::read(to)?;
+    let marks = parse_spec_file(&from_spec_file_path, Some(&source))?;
+    write_spec_file(&to_spec_file_path, &marks, Some(&source))
+}
+
+/// Reason a spec file is not needed anymore.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Garbage {
+    /// The source was deleted or moved.
+    Orphaned,
+    /// Nothing is marked, e.g. the spec was created by `marks status` of an older version.
+    Empty,
+}
+
+impl fmt::Display for Garbage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Garbage::Orphaned => f.write_str("orphaned"),
+            Garbage::Empty => f.write_str("empty"),
+        }
+    }
+}
+
+/// Check whether a spec file can be removed. Spec files which do not record their source are
+/// only removed when they are empty.
+pub fn find_garbage<P: AsRef<Path>>(spec_file_path: P) -> anyhow::Result<Option<Garbage>> {
+    let spec_file_path = spec_file_path.as_ref();
+    if let Some(source_path) = source_path_of_spec(spec_file_path)? {
+        if !source_path.is_file() {
+            return Ok(Some(Garbage::Orphaned));
+        }
+    }
+    // patterns only resolve against the source
+    let source = source_path_of_spec(spec_file_path)?
+        .map(SourceFile::read)
+        .transpose()
+        .ok()
+        .flatten();
+    if parse_spec_file(spec_file_path, source.as_ref())?.is_empty() {
+        return Ok(Some(Garbage::Empty));
+    }
+    Ok(None)
+}
+
+/// Path of the spec file of a source.
+///
+/// Inside a project with a `.marks` directory, the spec file is `.marks/<path>.spec` where
+/// `<path>` is the path of the source relative to the project, so specs can be shared with the
+/// project. Otherwise it is the SHA-256 of the absolute path of the source in
+/// `$XDG_DATA_HOME/marks`.
 pub fn get_spec_file_path<P: AsRef<Path>>(file_path: P) -> PathBuf {
-    let file_path = fs::canonicalize(file_path).expect("failed to get current directory");
+    let file_path = canonical_path(file_path).expect("failed to get current directory");
+
+    if let Some((store, relative)) = project_relative_path(&file_path) {
+        let mut relative = relative.into_os_string();
+        relative.push(SPEC_FILE_EXTENSION);
+        return store.join(relative);
+    }
 
     let mut hasher = sha2::Sha256::new();
     hasher.update(file_path.as_os_str().as_encoded_bytes());
@@ -22,274 +234,1724 @@ pub fn get_spec_file_path<P: AsRef<Path>>(file_path: P) -> PathBuf {
     get_spec_file_dir().join(PathBuf::from(format!("{:x}", result)))
 }
 
-pub fn touch_file<P: AsRef<Path>>(file_path: P) -> anyhow::Result<()> {
-    let file_path = file_path.as_ref();
-    if file_path.exists() {
-        return Ok(());
-    }
-    fs::create_dir_all(
-        file_path
-            .parent()
-            .ok_or(anyhow::anyhow!("failed to get parent directory"))?,
-    )?;
-    File::create(file_path)?;
-    Ok(())
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FileMarkStatus {
+    pub marked: u32,
+    pub line_no: u32,
+    // indexed by `Category as usize`
+    pub by_category: [u32; Category::ALL.len()],
 }
 
-#[derive(Copy, Clone, Debug)]
-pub struct FileMarkStatus {
-    pub marked: u16,
-    pub line_no: u16,
+impl FileMarkStatus {
+    /// Percentage of marked lines, 0 for empty sources.
+    pub fn percent(&self) -> f64 {
+        percent(self.marked as u64, self.line_no as u64)
+    }
+}
+
+/// Status of sources summed up, e.g. of a directory.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DirectoryStatus {
+    pub marked: u64,
+    pub line_no: u64,
+    // indexed by `Category as usize`
+    pub by_category: [u64; Category::ALL.len()],
+    pub files: u64,
+    /// Sources with any marked line
+    pub marked_files: u64,
+}
+
+impl DirectoryStatus {
+    pub fn add_file(&mut self, status: &FileMarkStatus) {
+        self.marked += status.marked as u64;
+        self.line_no += status.line_no as u64;
+        for (total, count) in self.by_category.iter_mut().zip(status.by_category) {
+            *total += count as u64;
+        }
+        self.files += 1;
+        if status.marked > 0 {
+            self.marked_files += 1;
+        }
+    }
+
+    pub fn add(&mut self, other: &DirectoryStatus) {
+        self.marked += other.marked;
+        self.line_no += other.line_no;
+        for (total, count) in self.by_category.iter_mut().zip(other.by_category) {
+            *total += count;
+        }
+        self.files += other.files;
+        self.marked_files += other.marked_files;
+    }
+
+    /// Percentage of marked lines, 0 for empty sources.
+    pub fn percent(&self) -> f64 {
+        percent(self.marked, self.line_no)
+    }
+}
+
+impl From<FileMarkStatus> for DirectoryStatus {
+    fn from(status: FileMarkStatus) -> Self {
+        let mut total = Self::default();
+        total.add_file(&status);
+        total
+    }
+}
+
+fn percent(marked: u64, line_no: u64) -> f64 {
+    if line_no == 0 {
+        return 0.0;
+    }
+    marked as f64 / line_no as f64 * 100.0
+}
+
+/// Marks of a source by category and notes on its lines, as stored in its spec file.
+#[derive(Clone, Debug, Default)]
+pub struct FileMarks {
+    specs: BTreeMap<Category, FileMarkSpec>,
+    notes: Vec<Note>,
+    comments: Vec<Comment>,
+    patterns: Vec<PatternSpec>,
+    source_path: Option<PathBuf>,
+}
+
+// `/pattern/` spec of a spec file, written back as it is while it marks the same lines
+#[derive(Clone, Debug)]
+struct PatternSpec {
+    // None for the notes section
+    section: Option<Category>,
+    // spec as written, e.g. "/^fn main/+1"
+    text: String,
+    // whole line of the spec file, e.g. with the text of a note
+    line: String,
+    // lines matched when parsed, None if the pattern matched nothing
+    range: Option<Range<u32>>,
+}
+
+// comment and blank lines of a spec file, written back above the spec they were above. Specs
+// are written sorted by their lines, so a comment moves with its spec when specs were not in
+// order, and merged specs are written below the comments of all of them.
+#[derive(Clone, Debug)]
+struct Comment {
+    // None for the notes section
+    section: Option<Category>,
+    place: CommentPlace,
+    lines: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum CommentPlace {
+    // above the header of the section
+    Header,
+    // above the first spec ending after this 0-index line, or at the end of the section
+    Spec(u32),
+}
+
+/// Free text attached to a line or range of a source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Note {
+    // 0-index, [l, r)
+    pub range: Range<u32>,
+    pub text: String,
+}
+
+impl FileMarks {
+    /// Path of the source as recorded in the spec file, see `source_path_of_spec`.
+    pub fn source_path(&self) -> Option<&Path> {
+        self.source_path.as_deref()
+    }
+
+    pub fn spec(&self, category: Category) -> Option<&FileMarkSpec> {
+        self.specs.get(&category)
+    }
+
+    pub fn spec_mut(&mut self, category: Category) -> &mut FileMarkSpec {
+        self.specs.entry(category).or_default()
+    }
+
+    pub fn set_spec(&mut self, category: Category, spec: FileMarkSpec) {
+        self.specs.insert(category, spec);
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (Category, &FileMarkSpec)> + '_ {
+        self.specs.iter().map(|(category, spec)| (*category, spec))
+    }
+
+    /// Category of a line. When categories overlap, e.g. in a hand-edited spec file, the later
+    /// category wins so that follow-ups and the like are not hidden by read marks.
+    pub fn category_of(&self, line_offset: u32) -> Option<Category> {
+        self.iter()
+            .rev()
+            .find(|(_, spec)| spec.match_line_offset(line_offset))
+            .map(|(category, _)| category)
+    }
+
+    pub fn match_line_offset(&self, line_offset: u32) -> bool {
+        self.category_of(line_offset).is_some()
+    }
+
+    /// Mark a line with `category`, moving it out of any other category.
+    pub fn mark(&mut self, line_offset: u32, category: Category) {
+        for (other, spec) in self.specs.iter_mut() {
+            if *other != category && spec.match_line_offset(line_offset) {
+                spec.remove(line_offset);
+            }
+        }
+        self.spec_mut(category).add(line_offset);
+    }
+
+    pub fn unmark(&mut self, line_offset: u32) {
+        for spec in self.specs.values_mut() {
+            if spec.match_line_offset(line_offset) {
+                spec.remove(line_offset);
+            }
+        }
+    }
+
+    /// Notes sorted by the first line.
+    pub fn notes(&self) -> &[Note] {
+        &self.notes
+    }
+
+    pub fn add_note(&mut self, range: Range<u32>, text: String) {
+        let idx = self
+            .notes
+            .partition_point(|note| note.range.start <= range.start);
+        self.notes.insert(idx, Note { range, text });
+    }
+
+    pub fn note_at(&self, line_offset: u32) -> Option<&Note> {
+        self.notes
+            .iter()
+            .find(|note| note.range.contains(&line_offset))
+    }
+
+    /// Replace the text of the note on a line, or add a note to the line if it has none.
+    /// An empty text removes the note.
+    pub fn set_note(&mut self, line_offset: u32, text: String) {
+        let idx = self
+            .notes
+            .iter()
+            .position(|note| note.range.contains(&line_offset));
+        match (idx, text.is_empty()) {
+            (Some(idx), true) => {
+                self.notes.remove(idx);
+            }
+            (Some(idx), false) => self.notes[idx].text = text,
+            (None, true) => {}
+            (None, false) => self.add_note(line_offset..line_offset.saturating_add(1), text),
+        }
+    }
+
+    // comments of a section, the notes section for None, sorted by place
+    fn comments_of(&self, section: Option<Category>) -> impl Iterator<Item = &Comment> + '_ {
+        self.comments
+            .iter()
+            .filter(move |comment| comment.section == section)
+    }
+
+    // patterns of a section, the notes section for None
+    fn patterns_of(&self, section: Option<Category>) -> impl Iterator<Item = &PatternSpec> + '_ {
+        self.patterns
+            .iter()
+            .filter(move |pattern| pattern.section == section)
+    }
+
+    /// Whether nothing is marked and there are no notes, nor patterns which may match again.
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+            && self.notes.is_empty()
+            && self.specs.values().all(|spec| match spec {
+                FileMarkSpec::All => false,
+                FileMarkSpec::Partial(specs) => specs.is_empty(),
+            })
+    }
+
+    /// Number of lines marked with any category in a source of `line_no` lines.
+    pub fn count_marked(&self, line_no: u32) -> u32 {
+        let mut marked = IntervalSet::new();
+        for spec in self.specs.values() {
+            match spec {
+                FileMarkSpec::All => return line_no,
+                FileMarkSpec::Partial(specs) => marked = marked.union(specs),
+            }
+        }
+        marked.count_in(0..line_no)
+    }
+
+    /// Status of a source of `line_no` lines with these marks.
+    pub fn status(&self, line_no: u32) -> FileMarkStatus {
+        let mut by_category = [0u32; Category::ALL.len()];
+        for (category, spec) in self.iter() {
+            by_category[category as usize] = spec.count_marked(line_no);
+        }
+        FileMarkStatus {
+            marked: self.count_marked(line_no),
+            line_no,
+            by_category,
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum FileMarkSpec {
     All,
-    Partial(Vec<SpecType>),
+    Partial(IntervalSet),
+}
+
+impl Default for FileMarkSpec {
+    fn default() -> Self {
+        FileMarkSpec::Partial(IntervalSet::new())
+    }
 }
 
 impl FileMarkSpec {
-    pub fn match_line_offset(&self, line_offset: u16) -> bool {
+    pub fn match_line_offset(&self, line_offset: u32) -> bool {
         match self {
             FileMarkSpec::All => true,
-            FileMarkSpec::Partial(specs) => {
-                for spec in specs.iter() {
-                    match *spec {
-                        SpecType::Line(offset) if offset == line_offset => return true,
-                        SpecType::Range(l, r) if l <= line_offset && line_offset < r => {
-                            return true
-                        }
-                        _ => continue,
-                    }
-                }
-                false
-            }
+            FileMarkSpec::Partial(specs) => specs.contains(line_offset),
         }
     }
 
-    pub fn add(&mut self, line_offset: u16) {
+    pub fn add(&mut self, line_offset: u32) {
         match self {
             FileMarkSpec::All => {}
-            FileMarkSpec::Partial(specs) => {
-                specs.push(SpecType::Line(line_offset));
-            }
+            FileMarkSpec::Partial(specs) => specs.insert(line_offset),
         }
     }
 
-    pub fn remove(&mut self, line_offset: u16) {
+    pub fn remove(&mut self, line_offset: u32) {
         match self {
             FileMarkSpec::All => {
-                let before = SpecType::Range(0, line_offset);
-                let after = SpecType::Range(line_offset + 1, u16::MAX);
-                *self = FileMarkSpec::Partial(vec![before, after]);
-            }
-            FileMarkSpec::Partial(specs) => {
-                let idx = specs.iter().enumerate().find_map(|(idx, spec)| match spec {
-                    SpecType::Line(offset) if *offset == line_offset => Some(idx),
-                    SpecType::Range(l, r) if *l <= line_offset && line_offset < *r => Some(idx),
-                    _ => None,
-                });
-                if let Some(idx) = idx {
-                    let spec = specs.get(idx).unwrap();
-                    match spec {
-                        SpecType::Line(_) => {
-                            specs.remove(idx);
-                        }
-                        SpecType::Range(l, r) => {
-                            let l = *l;
-                            let r = *r;
-
-                            if r - l == 2 {
-                                specs[idx] = SpecType::Line(l + 1);
-                                return;
-                            }
-
-                            if l == line_offset {
-                                specs[idx] = SpecType::Range(l + 1, r);
-                            } else if r == line_offset + 1 {
-                                specs[idx] = SpecType::Range(l, r - 1);
-                            } else {
-                                specs[idx] = SpecType::Range(l, line_offset);
-                                specs.insert(idx + 1, SpecType::Range(line_offset + 1, r));
-                            }
-                        }
-                    }
-                }
+                let mut specs = IntervalSet::new();
+                specs.insert_range(0..line_offset);
+                specs.insert_range(line_offset.saturating_add(1)..u32::MAX);
+                *self = FileMarkSpec::Partial(specs);
             }
+            FileMarkSpec::Partial(specs) => specs.remove(line_offset),
         }
     }
 
-    pub fn optimize(&mut self) {
+    /// Number of marked lines in a source of `line_no` lines.
+    pub fn count_marked(&self, line_no: u32) -> u32 {
         match self {
-            FileMarkSpec::All => {}
-            FileMarkSpec::Partial(specs) => {
-                let tmp = Self::rebuild_partial_specs(specs);
-                *specs = tmp;
+            FileMarkSpec::All => line_no,
+            FileMarkSpec::Partial(specs) => specs.count_in(0..line_no),
+        }
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        match (self, other) {
+            (FileMarkSpec::All, _) | (_, FileMarkSpec::All) => FileMarkSpec::All,
+            (FileMarkSpec::Partial(lhs), FileMarkSpec::Partial(rhs)) => {
+                FileMarkSpec::Partial(lhs.union(rhs))
             }
         }
     }
 
-    fn rebuild_partial_specs(specs: &Vec<SpecType>) -> Vec<SpecType> {
-        let mut line_offset_map = vec![false; u16::MAX as usize];
-        for spec in specs {
-            match *spec {
-                SpecType::Line(line_offset) => {
-                    line_offset_map[line_offset as usize] = true;
-                }
-                SpecType::Range(l, r) => {
-                    for line_offset in l..r {
-                        line_offset_map[line_offset as usize] = true;
-                    }
-                }
+    pub fn intersection(&self, other: &Self) -> Self {
+        match (self, other) {
+            (FileMarkSpec::All, spec) | (spec, FileMarkSpec::All) => spec.clone(),
+            (FileMarkSpec::Partial(lhs), FileMarkSpec::Partial(rhs)) => {
+                FileMarkSpec::Partial(lhs.intersection(rhs))
             }
         }
+    }
 
-        let mut result = vec![];
-        let mut left_value: Option<usize> = None;
-        for (line_offset, b) in line_offset_map.iter().enumerate() {
-            if left_value.is_none() && *b {
-                left_value = Some(line_offset);
-            } else if left_value.is_some() && !*b {
-                let left = left_value.take().unwrap();
-                if line_offset - left == 1 {
-                    result.push(SpecType::Line(left as u16));
-                } else {
-                    result.push(SpecType::Range(left as u16, line_offset as u16));
-                }
+    pub fn difference(&self, other: &Self) -> Self {
+        match (self, other) {
+            (_, FileMarkSpec::All) => FileMarkSpec::Partial(IntervalSet::new()),
+            (FileMarkSpec::All, FileMarkSpec::Partial(rhs)) => {
+                FileMarkSpec::Partial(rhs.complement(0..u32::MAX))
+            }
+            (FileMarkSpec::Partial(lhs), FileMarkSpec::Partial(rhs)) => {
+                FileMarkSpec::Partial(lhs.difference(rhs))
             }
         }
-        if let Some(left) = left_value {
-            result.push(SpecType::Range(left as u16, u16::MAX));
+    }
+
+    /// Unmarked lines of a source of `line_no` lines.
+    pub fn complement(&self, line_no: u32) -> Self {
+        match self {
+            FileMarkSpec::All => FileMarkSpec::Partial(IntervalSet::new()),
+            FileMarkSpec::Partial(specs) => FileMarkSpec::Partial(specs.complement(0..line_no)),
         }
+    }
+
+    /// Specs as they are written to a spec file, sorted by offset.
+    pub fn specs(&self) -> impl Iterator<Item = SpecType> + '_ {
+        let specs = match self {
+            FileMarkSpec::All => None,
+            FileMarkSpec::Partial(specs) => Some(specs.iter()),
+        };
+        specs.into_iter().flatten().map(|(l, r)| match r - l {
+            1 => SpecType::Line(l),
+            _ => SpecType::Range(l, r),
+        })
+    }
+}
 
-        result
+impl FromIterator<SpecType> for FileMarkSpec {
+    fn from_iter<T: IntoIterator<Item = SpecType>>(iter: T) -> Self {
+        FileMarkSpec::Partial(iter.into_iter().map(|spec| spec.range()).collect())
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub enum SpecType {
     // 0-index
-    Line(u16),
+    Line(u32),
     // 0-index, [l, r)
-    Range(u16, u16),
+    Range(u32, u32),
+}
+
+impl SpecType {
+    pub fn range(&self) -> Range<u32> {
+        match *self {
+            SpecType::Line(offset) => offset..offset.saturating_add(1),
+            SpecType::Range(l, r) => l..r,
+        }
+    }
+}
+
+/// Name of the per-directory ignore files of sources, in the format of `.gitignore`.
+pub const MARKS_IGNORE_FILE: &str = ".marksignore";
+
+/// Globs selecting the sources of a directory, relative to the directory. Sources ignored by
+/// `.gitignore` or `MARKS_IGNORE_FILE` are skipped as well.
+#[derive(Clone, Debug, Default)]
+pub struct SourceFilter {
+    /// Only sources matching any of them, if not empty
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
 }
 
-pub fn directory_status<P: AsRef<Path>>(dir_path: P) -> anyhow::Result<FileMarkStatus> {
+// never sources, though dot-directories like `.github` are
+const SKIPPED_FILE_NAMES: [&str; 5] = [
+    ".git",
+    PROJECT_STORE_DIR,
+    ".gitignore",
+    ".ignore",
+    MARKS_IGNORE_FILE,
+];
+
+/// Sources inside a directory, skipping ignored ones, ignore files and binary files.
+pub fn walk_sources<P: AsRef<Path>>(
+    dir_path: P,
+    filter: &SourceFilter,
+) -> anyhow::Result<Vec<PathBuf>> {
     let dir_path = dir_path.as_ref();
-    let mut marked = 0u16;
-    let mut line_no = 0u16;
-    for entry in fs::read_dir(dir_path)? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.is_dir() {
-            let status = directory_status(&path)?;
-            marked += status.marked;
-            line_no += status.line_no;
-        } else {
-            let status = file_status(&path)?;
-            marked += status.marked;
-            line_no += status.line_no;
+    let mut overrides = ignore::overrides::OverrideBuilder::new(dir_path);
+    for glob in &filter.include {
+        overrides.add(glob)?;
+    }
+    for glob in &filter.exclude {
+        overrides.add(&format!("!{}", glob))?;
+    }
+
+    let walker = ignore::WalkBuilder::new(dir_path)
+        .hidden(false)
+        .require_git(false)
+        .add_custom_ignore_filename(MARKS_IGNORE_FILE)
+        .overrides(overrides.build()?)
+        .filter_entry(|entry| {
+            !SKIPPED_FILE_NAMES
+                .iter()
+                .any(|name| entry.file_name() == *name)
+        })
+        .build_parallel();
+
+    let sources = Mutex::new(vec![]);
+    let error = Mutex::new(None);
+    walker.run(|| {
+        Box::new(|entry| {
+            let result = entry.map_err(anyhow::Error::from).and_then(|entry| {
+                let is_file = entry
+                    .file_type()
+                    .is_some_and(|file_type| file_type.is_file());
+                if is_file && !SourceFile::is_binary(entry.path())? {
+                    sources
+                        .lock()
+                        .expect("not poisoned")
+                        .push(entry.into_path());
+                }
+                Ok(())
+            });
+            match result {
+                Ok(()) => ignore::WalkState::Continue,
+                Err(err) => {
+                    *error.lock().expect("not poisoned") = Some(err);
+                    ignore::WalkState::Quit
+                }
+            }
+        })
+    });
+
+    if let Some(err) = error.into_inner().expect("not poisoned") {
+        return Err(err);
+    }
+    let mut sources = sources.into_inner().expect("not poisoned");
+    sources.sort();
+    Ok(sources)
+}
+
+/// Status of a directory with the status of each child, or of a single source.
+#[derive(Clone, Debug)]
+pub struct StatusTree {
+    pub path: PathBuf,
+    /// Sum of the children for directories
+    pub status: DirectoryStatus,
+    /// Subdirectories and sources by file name, empty for sources. Directories without
+    /// sources are left out.
+    pub children: BTreeMap<OsString, StatusTree>,
+}
+
+impl StatusTree {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            status: DirectoryStatus::default(),
+            children: BTreeMap::new(),
+        }
+    }
+
+    // add the status of a source at `relative` path below this node
+    fn insert(&mut self, relative: &Path, status: &FileMarkStatus) {
+        self.status.add_file(status);
+        if relative.as_os_str().is_empty() {
+            return;
         }
+
+        let mut components = relative.components();
+        let Some(name) = components.next() else {
+            return;
+        };
+        let name = name.as_os_str().to_owned();
+        let child_path = self.path.join(&name);
+        self.children
+            .entry(name)
+            .or_insert_with(|| StatusTree::new(child_path))
+            .insert(components.as_path(), status);
+    }
+}
+
+/// Status of the sources of a directory, counted in parallel, with the errors of invalid spec
+/// files. Sources with invalid spec files count as unmarked. Line counts of sources without
+/// spec files are taken from `cache` if possible.
+pub fn status_tree<P: AsRef<Path>>(
+    dir_path: P,
+    filter: &SourceFilter,
+    cache: &LineCountCache,
+) -> anyhow::Result<(StatusTree, Vec<ParseError>)> {
+    let dir_path = dir_path.as_ref();
+    let sources = walk_sources(dir_path, filter)?;
+    let statuses = sources
+        .par_iter()
+        .map(|path| match cached_file_status(path, cache) {
+            Ok(status) => Ok((status, None)),
+            Err(err) => {
+                let error = err.downcast::<ParseError>()?;
+                let status = FileMarkStatus {
+                    line_no: cache.line_count(path)?,
+                    ..Default::default()
+                };
+                Ok((status, Some(error)))
+            }
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut tree = StatusTree::new(dir_path.to_path_buf());
+    let mut errors = vec![];
+    for (path, (status, error)) in sources.iter().zip(statuses) {
+        tree.insert(path.strip_prefix(dir_path)?, &status);
+        errors.extend(error);
     }
-    Ok(FileMarkStatus { marked, line_no })
+    Ok((tree, errors))
+}
+
+pub fn directory_status<P: AsRef<Path>>(
+    dir_path: P,
+    filter: &SourceFilter,
+    cache: &LineCountCache,
+) -> anyhow::Result<DirectoryStatus> {
+    Ok(status_tree(dir_path, filter, cache)?.0.status)
 }
 
 pub fn file_status<P: AsRef<Path>>(file_path: P) -> anyhow::Result<FileMarkStatus> {
-    let file_path = file_path.as_ref();
+    cached_file_status(file_path.as_ref(), &LineCountCache::default())
+}
+
+fn cached_file_status(file_path: &Path, cache: &LineCountCache) -> anyhow::Result<FileMarkStatus> {
     let spec_file_path = get_spec_file_path(file_path);
-    touch_file(&spec_file_path)?;
+    if !spec_file_path.exists() {
+        return Ok(FileMarkStatus {
+            line_no: cache.line_count(file_path)?,
+            ..Default::default()
+        });
+    }
 
-    let spec = parse_spec_file(&spec_file_path)?;
+    let source = SourceFile::read(file_path)?;
+    let marks = parse_spec_file(&spec_file_path, Some(&source))?;
+    Ok(marks.status(source.lines.len() as u32))
+}
 
-    let mut line_no = 0u16;
-    let mut reader = BufReader::new(File::open(file_path)?);
-    let mut buf = String::new();
-    let mut marked = 0u16;
-    while reader.read_line(&mut buf)? > 0 {
-        if spec.match_line_offset(line_no) {
-            marked += 1;
+/// Environment variable naming the encoding of sources, e.g. `latin1` or `shift_jis`.
+/// Sources are decoded as UTF-8 by default.
+pub const ENCODING_ENV: &str = "MARKS_ENCODING";
+
+// bytes checked for NUL to detect binary files, the same as git
+const BINARY_CHECK_LEN: usize = 8000;
+
+/// Source file which marks are resolved against.
+pub struct SourceFile {
+    pub path: PathBuf,
+    /// Lines without line endings, which may be LF, CRLF or a lone CR.
+    pub lines: Vec<String>,
+    // line ending of each line, empty for the last line without one
+    endings: Vec<&'static str>,
+}
+
+impl SourceFile {
+    /// Read a text source. Invalid bytes of the encoding are replaced with U+FFFD, and binary
+    /// files are rejected.
+    pub fn read<P: AsRef<Path>>(source_file_path: P) -> anyhow::Result<Self> {
+        let path = source_file_path.as_ref().to_path_buf();
+        let bytes = fs::read(&path)?;
+        if is_binary(&bytes) {
+            return Err(anyhow::anyhow!("{}: binary file", path.display()));
         }
-        line_no += 1;
-        buf.clear();
+
+        let text = match env::var(ENCODING_ENV) {
+            Ok(label) => {
+                let encoding = encoding_rs::Encoding::for_label(label.as_bytes())
+                    .ok_or(anyhow::anyhow!("unknown encoding: {}", label))?;
+                encoding.decode(&bytes).0
+            }
+            Err(_) => String::from_utf8_lossy(&bytes),
+        };
+        let (lines, endings) = split_lines(&text);
+        let lines = lines.into_iter().map(String::from).collect();
+
+        Ok(Self {
+            path,
+            lines,
+            endings,
+        })
+    }
+
+    /// Original line ending of a line, empty for the last line without one.
+    pub fn line_ending(&self, line_offset: usize) -> &'static str {
+        self.endings.get(line_offset).copied().unwrap_or("")
+    }
+
+    /// Whether a file looks binary, i.e. has a NUL byte near the start.
+    pub fn is_binary<P: AsRef<Path>>(source_file_path: P) -> anyhow::Result<bool> {
+        let mut head = Vec::with_capacity(BINARY_CHECK_LEN);
+        File::open(source_file_path)?
+            .take(BINARY_CHECK_LEN as u64)
+            .read_to_end(&mut head)?;
+        Ok(is_binary(&head))
+    }
+}
+
+/// Split text into lines and their endings. LF, CRLF and a lone CR end a line.
+pub(crate) fn split_lines(text: &str) -> (Vec<&str>, Vec<&'static str>) {
+    let mut lines = vec![];
+    let mut endings = vec![];
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (line, ending) = match rest.find(['\r', '\n']) {
+            Some(i) if rest[i..].starts_with("\r\n") => (&rest[..i], "\r\n"),
+            Some(i) if rest[i..].starts_with('\r') => (&rest[..i], "\r"),
+            Some(i) => (&rest[..i], "\n"),
+            None => (rest, ""),
+        };
+        lines.push(line);
+        endings.push(ending);
+        rest = &rest[line.len() + ending.len()..];
     }
+    (lines, endings)
+}
 
-    Ok(FileMarkStatus { marked, line_no })
+fn is_binary(bytes: &[u8]) -> bool {
+    bytes[..bytes.len().min(BINARY_CHECK_LEN)].contains(&0)
 }
 
 pub const ALL_MAGIC: &str = "-*- all -*-";
 
-static NUM_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\s*(\d+)\s*$").unwrap());
-static RANGE_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\s*(\d+)\s*-\s*(\d+)\s*$").unwrap());
+// end of ranges open to the end in spec files without `#! format`, when offsets were u16
+const LEGACY_END: u32 = u16::MAX as u32;
 
-pub fn parse_spec_file<P: AsRef<Path>>(file_path: P) -> anyhow::Result<FileMarkSpec> {
-    let mut specs = Vec::new();
+/// Version of the spec file format written by this version.
+pub const SPEC_FORMAT_VERSION: u32 = 1;
 
-    let mut buf = String::new();
-    let mut reader = BufReader::new(fs::File::open(file_path)?);
-    while reader.read_line(&mut buf)? > 0 {
-        let line = buf.trim_end_matches('\n');
+// meta data of spec file: "#! <key> <value>"
+const META_PREFIX: &str = "#! ";
+const META_FORMAT: &str = "format";
+// path of the source, see `recorded_source_path`
+const META_SOURCE: &str = "source";
+// git blob of the source the offsets of a spec file refer to
+const META_GIT_BLOB: &str = "git-blob";
+
+// "N"
+static NUM_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\s*(\d+)\s*(?:@(\S+))?\s*$").unwrap());
+// "A-B", "A..B" (B exclusive) or "A..=B" (B inclusive), where either end may be left out
+static RANGE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^\s*(\d+)?\s*(-|\.\.=|\.\.)\s*(\d+)?\s*(?:@(\S+))?\s*$").unwrap());
+// "/pattern/" or "/pattern/+N"
+static PATTERN_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^\s*/(.+)/(?:\+(\d+))?\s*$").unwrap());
+
+fn parse_anchor(s: &str) -> Result<Option<Anchor>, ParseErrorKind> {
+    if s.is_empty() {
+        return Ok(None);
+    }
+    let anchor = s
+        .parse()
+        .map_err(|_| ParseErrorKind::InvalidAnchor(s.to_string()))?;
+    Ok(Some(anchor))
+}
+
+// head and tail anchors of a spec: "<head>" for a line, "<head>/<tail>" for a range
+fn parse_anchors(s: Option<&str>) -> Result<(Option<Anchor>, Option<Anchor>), ParseErrorKind> {
+    let Some(s) = s else {
+        return Ok((None, None));
+    };
+    match s.split_once('/') {
+        Some((head, tail)) => Ok((parse_anchor(head)?, parse_anchor(tail)?)),
+        None => Ok((parse_anchor(s)?, None)),
+    }
+}
+
+/// Move a spec to where its anchored lines are in the current source.
+///
+/// Specs without anchors are kept as they are. Returns `None` when none of the anchored
+/// lines can be found anymore.
+fn relocate_spec(
+    spec: SpecType,
+    head: Option<Anchor>,
+    tail: Option<Anchor>,
+    index: &AnchorIndex,
+) -> Option<SpecType> {
+    if head.is_none() && tail.is_none() {
+        return Some(spec);
+    }
+    match spec {
+        SpecType::Line(offset) => {
+            let head = head?;
+            let offset = index.resolve(&head, offset as usize)?;
+            Some(SpecType::Line(offset as u32))
+        }
+        // open to the end of the source
+        SpecType::Range(l, u32::MAX) => {
+            let l = index.resolve(&head?, l as usize)?;
+            Some(SpecType::Range(l as u32, u32::MAX))
+        }
+        SpecType::Range(l, r) => {
+            let len = r.saturating_sub(l) as usize;
+            let head = head.and_then(|anchor| index.resolve(&anchor, l as usize));
+            let tail = tail.and_then(|anchor| index.resolve(&anchor, r.saturating_sub(1) as usize));
+            let (l, r) = match (head, tail) {
+                (Some(l), Some(t)) if l <= t => (l, t + 1),
+                (Some(l), _) => (l, l + len),
+                (None, Some(t)) => ((t + 1).saturating_sub(len), t + 1),
+                (None, None) => return None,
+            };
+            Some(SpecType::Range(l as u32, r as u32))
+        }
+    }
+}
+
+/// Move a spec along a line map from `git::line_map`, splitting it around changed lines and
+/// dropping it when all of its lines are gone.
+fn remap_spec(spec: SpecType, line_map: &[Option<usize>]) -> Vec<SpecType> {
+    // a range open to the end stays open from its first remaining line
+    if let SpecType::Range(l, u32::MAX) = spec {
+        let l = line_map.get(l as usize..).unwrap_or_default();
+        return match l.iter().flatten().next() {
+            Some(l) => vec![SpecType::Range(*l as u32, u32::MAX)],
+            None => vec![],
+        };
+    }
+    let (l, r) = match spec {
+        SpecType::Line(offset) => (offset as usize, offset as usize + 1),
+        SpecType::Range(l, r) => (l as usize, r as usize),
+    };
+
+    let mut result = vec![];
+    let mut current: Option<(usize, usize)> = None;
+    for new_offset in line_map[l.min(line_map.len())..r.min(line_map.len())]
+        .iter()
+        .flatten()
+    {
+        current = match current {
+            Some((l, r)) if r == *new_offset => Some((l, r + 1)),
+            Some((l, r)) => {
+                result.push((l, r));
+                Some((*new_offset, new_offset + 1))
+            }
+            None => Some((*new_offset, new_offset + 1)),
+        };
+    }
+    result.extend(current);
+
+    result
+        .into_iter()
+        .map(|(l, r)| match r - l {
+            1 => SpecType::Line(l as u32),
+            _ => SpecType::Range(l as u32, r as u32),
+        })
+        .collect()
+}
+
+// what a spec of a spec file stands for
+enum EntryKind {
+    Mark(Category),
+    Note(String),
+}
+
+// spec as written in a spec file, before it is resolved against the source
+struct SpecEntry {
+    kind: EntryKind,
+    spec: SpecType,
+    head: Option<Anchor>,
+    tail: Option<Anchor>,
+    // pattern as written, already resolved against the current source
+    pattern: Option<String>,
+    // comment and blank lines above the spec
+    comment: Vec<String>,
+    // 1-based line of the spec file and its text, for warnings
+    line_no: usize,
+    text: String,
+}
+/// How the specs of a spec file are resolved against the current source, see `parse_spec_file`.
+enum Relocation {
+    Keep,
+    LineMap(Vec<Option<usize>>),
+    Anchors(AnchorIndex),
+}
+
+impl Relocation {
+    fn new(git_blob: Option<String>, source: &SourceFile) -> Self {
+        let current_blob = git_blob.as_ref().and_then(|_| git::blob_id(&source.path));
+        if current_blob.is_some() && current_blob == git_blob {
+            return Relocation::Keep;
+        }
+        if let Some(line_map) = git_blob.and_then(|blob| git::line_map(source, &blob)) {
+            return Relocation::LineMap(line_map);
+        }
+        Relocation::Anchors(AnchorIndex::new(&source.lines))
+    }
+
+    fn apply(&self, entry: &SpecEntry) -> Vec<SpecType> {
+        match self {
+            Relocation::Keep => vec![entry.spec],
+            Relocation::LineMap(line_map) => remap_spec(entry.spec, line_map),
+            Relocation::Anchors(index) => relocate_spec(entry.spec, entry.head, entry.tail, index)
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+// section of spec file holding notes instead of marks
+const NOTES_SECTION: &str = "notes";
+
+// 1-based line number of a spec
+fn parse_line_number(s: &str) -> Result<u32, ParseErrorKind> {
+    match s.parse() {
+        Ok(0) => Err(ParseErrorKind::ZeroLine),
+        Ok(num) => Ok(num),
+        Err(_) => Err(ParseErrorKind::InvalidSpec),
+    }
+}
+
+// first line matching a pattern and the given number of lines after it
+fn parse_pattern(line: &str) -> Result<Option<(Regex, u32)>, ParseErrorKind> {
+    let Some(cap) = PATTERN_REGEX.captures(line) else {
+        return Ok(None);
+    };
+    let pattern =
+        Regex::new(&cap[1]).map_err(|_| ParseErrorKind::InvalidPattern(cap[1].to_string()))?;
+    let after = match cap.get(2) {
+        Some(after) => after
+            .as_str()
+            .parse()
+            .map_err(|_| ParseErrorKind::InvalidSpec)?,
+        None => 0,
+    };
+    Ok(Some((pattern, after)))
+}
+
+// spec and text of a note "<spec>: <text>", where a pattern of the spec may contain ':'
+fn split_note(line: &str) -> Option<(&str, &str)> {
+    let is_spec = |spec: &str| {
+        [&*PATTERN_REGEX, &*NUM_REGEX, &*RANGE_REGEX]
+            .iter()
+            .any(|regex| regex.is_match(spec))
+    };
+    line.match_indices(':')
+        .map(|(idx, _)| (&line[..idx], &line[idx + 1..]))
+        .find(|(spec, _)| is_spec(spec))
+        // reported as an invalid spec
+        .or_else(|| line.split_once(':'))
+}
 
-        if line.is_empty() {
+// None for a pattern which matches no line of the source. `legacy` is for spec files without
+// `#! format`.
+fn parse_spec_entry(
+    line: &str,
+    kind: EntryKind,
+    source: Option<&SourceFile>,
+    legacy: bool,
+) -> Result<Option<SpecEntry>, ParseErrorKind> {
+    let mut entry = SpecEntry {
+        kind,
+        spec: SpecType::Line(0),
+        head: None,
+        tail: None,
+        pattern: None,
+        comment: vec![],
+        line_no: 0,
+        text: String::new(),
+    };
+
+    if let Some((pattern, after)) = parse_pattern(line)? {
+        let Some(offset) =
+            source.and_then(|source| source.lines.iter().position(|line| pattern.is_match(line)))
+        else {
+            return Ok(None);
+        };
+        let offset = offset as u32;
+        entry.spec = match after {
+            0 => SpecType::Line(offset),
+            after => SpecType::Range(offset, offset.saturating_add(after).saturating_add(1)),
+        };
+        entry.pattern = Some(line.trim().to_string());
+    } else if let Some(cap) = NUM_REGEX.captures(line) {
+        let num = parse_line_number(&cap[1])?;
+        entry.spec = SpecType::Line(num - 1);
+        (entry.head, entry.tail) = parse_anchors(cap.get(2).map(|m| m.as_str()))?;
+    } else if let Some(cap) = RANGE_REGEX.captures(line) {
+        let from = cap
+            .get(1)
+            .map(|m| parse_line_number(m.as_str()))
+            .transpose()?;
+        let to = cap
+            .get(3)
+            .map(|m| parse_line_number(m.as_str()))
+            .transpose()?;
+        let start = match from {
+            Some(from) => from - 1,
+            None => 0,
+        };
+        let end = match (&cap[2], to) {
+            (_, None) if from.is_none() => return Err(ParseErrorKind::InvalidSpec),
+            (_, None) => u32::MAX,
+            ("..=", Some(to)) => to,
+            // `..` and `..=` were never written with u16 offsets
+            ("-", Some(LEGACY_END)) if legacy => u32::MAX,
+            ("-", Some(u32::MAX)) => u32::MAX,
+            (_, Some(to)) => to - 1,
+        };
+        entry.spec = SpecType::Range(start, end);
+        (entry.head, entry.tail) = parse_anchors(cap.get(4).map(|m| m.as_str()))?;
+    } else {
+        return Err(ParseErrorKind::InvalidSpec);
+    }
+    Ok(Some(entry))
+}
+
+/// Parse a spec file.
+///
+/// Specs before any `[category]` section are read marks. Specs in the `[notes]` section are
+/// followed by `: <text>`.
+///
+/// When `source` is given, specs are moved to where their lines are in it, so marks follow
+/// their lines after the source is edited. The git history of the source is used when the spec
+/// file records the blob it was written against, and the anchors of each spec otherwise.
+///
+/// Malformed lines are reported as `ParseError`.
+pub fn parse_spec_file<P: AsRef<Path>>(
+    file_path: P,
+    source: Option<&SourceFile>,
+) -> anyhow::Result<FileMarks> {
+    Ok(parse_spec_file_with_warnings(file_path, source)?.0)
+}
+
+/// Parse a spec file like `parse_spec_file`, and report lines which are accepted but probably
+/// not what was meant.
+pub fn parse_spec_file_with_warnings<P: AsRef<Path>>(
+    file_path: P,
+    source: Option<&SourceFile>,
+) -> anyhow::Result<(FileMarks, Vec<ParseWarning>)> {
+    let file_path = file_path.as_ref();
+    let mut entries = Vec::new();
+    let mut warnings = vec![];
+    // comment and blank lines above the current line
+    let mut comment = vec![];
+    let mut comments = vec![];
+    let mut patterns = vec![];
+    let mut all = BTreeSet::new();
+    // None in the notes section
+    let mut category = Some(Category::default());
+    let mut git_blob = None;
+    let mut source_path = None;
+    let mut legacy = true;
+
+    let reader = BufReader::new(fs::File::open(file_path)?);
+    for (idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line_no = idx + 1;
+        let error = |kind| ParseError {
+            path: file_path.to_path_buf(),
+            line_no,
+            text: line.clone(),
+            kind,
+        };
+
+        // blank lines are kept with comments to keep the grouping of specs
+        if line.trim().is_empty() {
+            comment.push(String::new());
+            continue;
+        }
+
+        if let Some(meta) = line.strip_prefix(META_PREFIX) {
+            let (key, value) = meta.split_once(' ').unwrap_or((meta, ""));
+            let value = value.trim();
+            match key {
+                META_FORMAT => {
+                    let version: u32 = value
+                        .parse()
+                        .map_err(|_| error(ParseErrorKind::InvalidMeta(key.to_string())))?;
+                    if version > SPEC_FORMAT_VERSION {
+                        return Err(error(ParseErrorKind::UnsupportedFormat(version)).into());
+                    }
+                    legacy = false;
+                }
+                META_SOURCE => source_path = Some(PathBuf::from(value)),
+                META_GIT_BLOB => git_blob = Some(value.to_string()),
+                // written by newer versions
+                _ => {}
+            }
             continue;
         }
 
         // comment
         if line.starts_with('#') {
+            comment.push(line);
             continue;
         }
 
-        // all magic comment
-        if line.contains(ALL_MAGIC) {
-            return Ok(FileMarkSpec::All);
+        // category or notes section
+        if let Some(name) = line
+            .trim()
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            category = match name.trim() {
+                NOTES_SECTION => None,
+                name => Some(
+                    name.parse()
+                        .map_err(|_| error(ParseErrorKind::UnknownCategory(name.to_string())))?,
+                ),
+            };
+            if !comment.is_empty() {
+                comments.push(Comment {
+                    section: category,
+                    place: CommentPlace::Header,
+                    lines: std::mem::take(&mut comment),
+                });
+            }
+            continue;
         }
 
-        let spec;
-        if let Some(cap) = RANGE_REGEX.captures(line) {
-            let from_str = &cap[1];
-            let to_str = &cap[2];
-            let from: u16 = from_str.parse()?;
-            let to: u16 = to_str.parse()?;
+        let (spec, kind) = match category {
+            None => {
+                let (spec, text) =
+                    split_note(&line).ok_or_else(|| error(ParseErrorKind::InvalidNote))?;
+                let text = text.strip_prefix(' ').unwrap_or(text).to_string();
+                (spec, EntryKind::Note(text))
+            }
+            // all magic comment
+            Some(category) if line.trim() == ALL_MAGIC => {
+                all.insert(category);
+                if !comment.is_empty() {
+                    comments.push(Comment {
+                        section: Some(category),
+                        place: CommentPlace::Spec(0),
+                        lines: std::mem::take(&mut comment),
+                    });
+                }
+                continue;
+            }
+            Some(category) => (line.as_str(), EntryKind::Mark(category)),
+        };
+        let Some(entry) = parse_spec_entry(spec, kind, source, legacy).map_err(error)? else {
+            // kept to be written back, as it may match again after the source is edited
+            patterns.push(PatternSpec {
+                section: category,
+                text: spec.trim().to_string(),
+                line: line.trim().to_string(),
+                range: None,
+            });
+            warnings.push(ParseWarning {
+                path: file_path.to_path_buf(),
+                line_no,
+                text: line,
+                kind: ParseWarningKind::PatternNotFound,
+            });
+            continue;
+        };
+        entries.push(SpecEntry {
+            line_no,
+            text: line,
+            comment: std::mem::take(&mut comment),
+            ..entry
+        });
+    }
+    // blank lines at the end are not worth keeping
+    while comment.last().is_some_and(String::is_empty) {
+        comment.pop();
+    }
+    if !comment.is_empty() {
+        comments.push(Comment {
+            section: category,
+            place: CommentPlace::Spec(u32::MAX),
+            lines: comment,
+        });
+    }
+
+    let relocation = match source {
+        Some(source) => Relocation::new(git_blob, source),
+        None => Relocation::Keep,
+    };
+    let line_count = source.map(|source| source.lines.len());
 
-            spec = SpecType::Range(from.saturating_sub(1), to.saturating_sub(1));
-        } else if let Some(cap) = NUM_REGEX.captures(line) {
-            let num_str = &cap[1];
-            let num: u16 = num_str.parse()?;
-            spec = SpecType::Line(num.saturating_sub(1));
-        } else {
-            return Err(anyhow::anyhow!("invalid spec format"));
+    let mut marks = FileMarks {
+        source_path,
+        ..Default::default()
+    };
+    for entry in entries {
+        let warning = |kind| ParseWarning {
+            path: file_path.to_path_buf(),
+            line_no: entry.line_no,
+            text: entry.text.clone(),
+            kind,
+        };
+        let specs = match entry.spec {
+            SpecType::Range(l, r) if l >= r => {
+                warnings.push(warning(ParseWarningKind::EmptyRange));
+                vec![]
+            }
+            _ if entry.pattern.is_some() => vec![entry.spec],
+            _ => relocation.apply(&entry),
+        };
+        let section = match &entry.kind {
+            EntryKind::Mark(category) => Some(*category),
+            EntryKind::Note(_) => None,
+        };
+        if let Some(text) = entry.pattern {
+            patterns.push(PatternSpec {
+                section,
+                text,
+                line: entry.text.trim().to_string(),
+                range: Some(entry.spec.range()),
+            });
+        }
+        if !entry.comment.is_empty() {
+            // the comment of a dropped spec stays about where the spec was
+            let line_offset = specs.first().unwrap_or(&entry.spec).range().start;
+            comments.push(Comment {
+                section,
+                place: CommentPlace::Spec(line_offset),
+                lines: entry.comment,
+            });
+        }
+        // ranges open to the end are not beyond the source
+        if let Some(line_count) = line_count {
+            let beyond = specs.iter().any(|spec| {
+                let end = spec.range().end;
+                end != u32::MAX && end as usize > line_count
+            });
+            if beyond {
+                warnings.push(warning(ParseWarningKind::BeyondSource(line_count)));
+            }
+        }
+
+        match entry.kind {
+            EntryKind::Mark(category) => {
+                for spec in specs {
+                    if let FileMarkSpec::Partial(set) = marks.spec_mut(category) {
+                        set.insert_range(spec.range());
+                    }
+                }
+            }
+            EntryKind::Note(text) => {
+                // a note stays in one piece even if some of its lines are gone
+                if let (Some(first), Some(last)) = (specs.first(), specs.last()) {
+                    marks.add_note(first.range().start..last.range().end, text);
+                }
+            }
         }
-        specs.push(spec);
-        buf.clear();
     }
+    for category in all {
+        marks.set_spec(category, FileMarkSpec::All);
+    }
+    // comments of a place stay in the order of the spec file
+    comments.sort_by_key(|comment| comment.place);
+    marks.comments = comments;
+    marks.patterns = patterns;
+    Ok((marks, warnings))
+}
 
-    Ok(FileMarkSpec::Partial(specs))
+/// Marks of a source from its spec file. A missing spec file means nothing is marked, so
+/// sources are not given spec files until marks are written.
+pub fn read_marks<P: AsRef<Path>>(
+    spec_file_path: P,
+    source: Option<&SourceFile>,
+) -> anyhow::Result<FileMarks> {
+    if !spec_file_path.as_ref().exists() {
+        return Ok(FileMarks::default());
+    }
+    parse_spec_file(spec_file_path, source)
 }
 
-pub fn write_spec_file<P: AsRef<Path>>(file_path: P, spec: &FileMarkSpec) -> anyhow::Result<()> {
+/// Write a spec file.
+///
+/// When `source` is given, every spec is written with the anchors of its lines, ranges are
+/// clipped to the length of the source, and the git blob of the source is recorded.
+pub fn write_spec_file<P: AsRef<Path>>(
+    file_path: P,
+    marks: &FileMarks,
+    source: Option<&SourceFile>,
+) -> anyhow::Result<()> {
+    let file_path = file_path.as_ref();
+    if let Some(dir) = file_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(file_path, format_spec(marks, source)?)?;
+    Ok(())
+}
+
+/// Format marks as the content of a spec file, see `write_spec_file`.
+///
+/// Specs of each section are sorted by their lines and overlapping ones are merged. Comments
+/// and blank lines of a parsed spec file are kept above the specs they were above.
+pub fn format_spec(marks: &FileMarks, source: Option<&SourceFile>) -> anyhow::Result<String> {
     use std::fmt::Write as fmtWrite;
     let mut buf = String::new();
-    match spec {
-        FileMarkSpec::All => {
-            buf.write_str(ALL_MAGIC)?;
-            buf.write_char('\n')?;
-        }
-        FileMarkSpec::Partial(specs) => {
-            for spec in specs {
-                match spec {
-                    SpecType::Line(offset) => {
-                        buf.write_str(&format!("{}\n", offset.saturating_add(1)))?;
-                    }
-                    SpecType::Range(l, r) => {
-                        buf.write_str(&format!(
-                            "{}-{}\n",
-                            l.saturating_add(1),
-                            r.saturating_add(1)
-                        ))?;
-                    }
+
+    buf.write_str(&format!(
+        "{}{} {}\n",
+        META_PREFIX, META_FORMAT, SPEC_FORMAT_VERSION
+    ))?;
+    if let Some(source) = source {
+        let source_path = recorded_source_path(&source.path)?;
+        buf.write_str(&format!(
+            "{}{} {}\n",
+            META_PREFIX,
+            META_SOURCE,
+            source_path.display()
+        ))?;
+    }
+
+    let partial = marks
+        .iter()
+        .any(|(_, spec)| matches!(spec, FileMarkSpec::Partial(_)));
+    if partial {
+        if let Some(blob) = source.and_then(|source| git::blob_id(&source.path)) {
+            buf.write_str(&format!("{}{} {}\n", META_PREFIX, META_GIT_BLOB, blob))?;
+        }
+    }
+
+    let source_lines = source.map(|source| source.lines.as_slice());
+    for category in Category::ALL {
+        let mut comments = marks.comments_of(Some(category)).peekable();
+        let mut patterns = marks.patterns_of(Some(category)).peekable();
+        let spec = match marks.spec(category) {
+            Some(FileMarkSpec::Partial(specs)) if specs.is_empty() => None,
+            spec => spec,
+        };
+        if spec.is_none() && comments.peek().is_none() && patterns.peek().is_none() {
+            continue;
+        }
+        write_comments(&mut buf, &mut comments, CommentPlace::Spec(0));
+        if category != Category::default() {
+            buf.write_str(&format!("[{}]\n", category))?;
+        }
+        match spec {
+            Some(FileMarkSpec::All) => {
+                write_comments(&mut buf, &mut comments, CommentPlace::Spec(u32::MAX));
+                buf.write_str(ALL_MAGIC)?;
+                buf.write_char('\n')?;
+            }
+            spec => {
+                // None may still have patterns which matched nothing
+                let no_specs = IntervalSet::new();
+                let specs = match spec {
+                    Some(FileMarkSpec::Partial(specs)) => specs,
+                    _ => &no_specs,
+                };
+                for (range, line) in format_marks(specs, patterns, source_lines) {
+                    write_comments(&mut buf, &mut comments, CommentPlace::Spec(range.end));
+                    buf.write_str(&format!("{}\n", line))?;
                 }
             }
         }
+        for comment in comments {
+            write_comment(&mut buf, comment);
+        }
+    }
+
+    let mut comments = marks.comments_of(None).peekable();
+    let notes = format_notes(marks, source_lines);
+    if notes.is_empty() && comments.peek().is_none() {
+        return Ok(buf);
+    }
+    write_comments(&mut buf, &mut comments, CommentPlace::Spec(0));
+    buf.write_str(&format!("[{}]\n", NOTES_SECTION))?;
+    for (range, line) in notes {
+        write_comments(&mut buf, &mut comments, CommentPlace::Spec(range.end));
+        buf.write_str(&format!("{}\n", line))?;
+    }
+    for comment in comments {
+        write_comment(&mut buf, comment);
+    }
+    Ok(buf)
+}
+
+// lines of the marks of a section with the lines they mark, sorted by the first line. Patterns
+// which still mark the same lines are written as they are, and those which matched nothing are
+// written last.
+fn format_marks<'a>(
+    specs: &IntervalSet,
+    patterns: impl Iterator<Item = &'a PatternSpec>,
+    source_lines: Option<&[String]>,
+) -> Vec<(Range<u32>, String)> {
+    let mut lines = vec![];
+    let mut offsets = specs.clone();
+    for pattern in patterns {
+        match &pattern.range {
+            None => lines.push((u32::MAX..u32::MAX, pattern.line.clone())),
+            Some(range) if specs.count_in(range.clone()) as usize == range.len() => {
+                offsets.remove_range(range.clone());
+                lines.push((range.clone(), pattern.text.clone()));
+            }
+            // some of its lines were unmarked since
+            Some(_) => {}
+        }
+    }
+    for spec in FileMarkSpec::Partial(offsets).specs() {
+        if let Some(line) = format_spec_type(spec, source_lines) {
+            lines.push((spec.range(), line));
+        }
+    }
+    lines.sort_by_key(|(range, _)| range.start);
+    lines
+}
+
+// lines of the notes section like `format_marks`, with patterns of notes on the same lines
+fn format_notes(marks: &FileMarks, source_lines: Option<&[String]>) -> Vec<(Range<u32>, String)> {
+    let mut lines = vec![];
+    let mut patterns: Vec<&PatternSpec> = marks.patterns_of(None).collect();
+    for note in marks.notes() {
+        let pattern = patterns
+            .iter()
+            .position(|pattern| pattern.range.as_ref() == Some(&note.range));
+        let spec = match pattern {
+            Some(idx) => Some(patterns.remove(idx).text.clone()),
+            None => {
+                let spec = match note.range.len() {
+                    1 => SpecType::Line(note.range.start),
+                    _ => SpecType::Range(note.range.start, note.range.end),
+                };
+                format_spec_type(spec, source_lines)
+            }
+        };
+        if let Some(spec) = spec {
+            lines.push((note.range.clone(), format!("{}: {}", spec, note.text)));
+        }
+    }
+    for pattern in patterns {
+        if pattern.range.is_none() {
+            lines.push((u32::MAX..u32::MAX, pattern.line.clone()));
+        }
+    }
+    lines.sort_by_key(|(range, _)| range.start);
+    lines
+}
+
+// write the comments placed before `place`
+fn write_comments<'a>(
+    buf: &mut String,
+    comments: &mut std::iter::Peekable<impl Iterator<Item = &'a Comment>>,
+    place: CommentPlace,
+) {
+    while let Some(comment) = comments.next_if(|comment| comment.place < place) {
+        write_comment(buf, comment);
+    }
+}
+
+fn write_comment(buf: &mut String, comment: &Comment) {
+    for line in &comment.lines {
+        buf.push_str(line);
+        buf.push('\n');
+    }
+}
+
+// a spec as a line of spec file, or None if it is outside of the source
+fn format_spec_type(spec: SpecType, source_lines: Option<&[String]>) -> Option<String> {
+    match (spec, source_lines) {
+        (SpecType::Line(offset), None) => Some(format!("{}", offset.saturating_add(1))),
+        (SpecType::Line(offset), Some(source_lines)) => {
+            let head = Anchor::at(source_lines, offset as usize)?;
+            Some(format!("{} @{}", offset.saturating_add(1), head))
+        }
+        (SpecType::Range(l, u32::MAX), None) => Some(format!("{}-", l.saturating_add(1))),
+        (SpecType::Range(l, u32::MAX), Some(source_lines)) => {
+            let head = Anchor::at(source_lines, l as usize)?;
+            Some(format!("{}- @{}/", l.saturating_add(1), head))
+        }
+        // inclusive, which reads as the lines it marks
+        (SpecType::Range(l, r), None) => Some(format!("{}..={}", l.saturating_add(1), r)),
+        (SpecType::Range(l, r), Some(source_lines)) => {
+            let r = r.min(source_lines.len() as u32);
+            if l >= r {
+                return None;
+            }
+            let head = Anchor::at(source_lines, l as usize)?;
+            let tail = Anchor::at(source_lines, r as usize - 1)?;
+            Some(format!(
+                "{}..={} @{}/{}",
+                l.saturating_add(1),
+                r,
+                head,
+                tail
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_spec(spec: &str) -> anyhow::Result<(FileMarks, Vec<ParseWarning>)> {
+        let file = tempfile::NamedTempFile::new()?;
+        fs::write(file.path(), spec)?;
+        parse_spec_file_with_warnings(file.path(), None)
+    }
+
+    fn parse_error(spec: &str) -> ParseError {
+        let err = parse_spec(spec).expect_err("should not parse");
+        err.downcast::<ParseError>().expect("parse error")
+    }
+
+    fn read_ranges(marks: &FileMarks) -> Vec<(u32, u32)> {
+        match marks.spec(Category::Read) {
+            Some(FileMarkSpec::Partial(specs)) => specs.iter().collect(),
+            spec => panic!("unexpected {:?}", spec),
+        }
+    }
+
+    #[test]
+    fn range_ending_at_legacy_end_round_trips() {
+        let mut marks = FileMarks::default();
+        if let FileMarkSpec::Partial(specs) = marks.spec_mut(Category::Read) {
+            specs.insert_range(0..65534);
+        }
+        let spec = format_spec(&marks, None).unwrap();
+        let (marks, _) = parse_spec(&spec).unwrap();
+        assert_eq!(read_ranges(&marks), vec![(0, 65534)]);
+    }
+
+    #[test]
+    fn legacy_end_is_open_only_without_format() {
+        let (marks, _) = parse_spec("1-65535\n").unwrap();
+        assert_eq!(read_ranges(&marks), vec![(0, u32::MAX)]);
+        let (marks, _) = parse_spec("#! format 1\n1-65535\n").unwrap();
+        assert_eq!(read_ranges(&marks), vec![(0, 65534)]);
+    }
+
+    #[test]
+    fn invalid_specs_are_rejected() {
+        let error = parse_error("1\nfoo 12\n");
+        assert_eq!(error.line_no, 2);
+        assert_eq!(error.text, "foo 12");
+        assert!(matches!(error.kind, ParseErrorKind::InvalidSpec));
+        assert!(matches!(
+            parse_error("12 foo\n").kind,
+            ParseErrorKind::InvalidSpec
+        ));
+        assert!(matches!(parse_error("0\n").kind, ParseErrorKind::ZeroLine));
+        assert!(matches!(
+            parse_error("0-3\n").kind,
+            ParseErrorKind::ZeroLine
+        ));
+        assert!(matches!(
+            parse_error("[nope]\n1\n").kind,
+            ParseErrorKind::UnknownCategory(name) if name == "nope"
+        ));
+        assert!(matches!(
+            parse_error("[notes]\n1 hello\n").kind,
+            ParseErrorKind::InvalidNote
+        ));
+    }
+
+    #[test]
+    fn empty_range_is_a_warning() {
+        let (marks, warnings) = parse_spec("5-3\n7\n").unwrap();
+        assert_eq!(read_ranges(&marks), vec![(6, 7)]);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].line_no, 1);
+        assert!(matches!(warnings[0].kind, ParseWarningKind::EmptyRange));
+    }
+
+    #[test]
+    fn spec_beyond_source_is_a_warning() {
+        let source_file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(source_file.path(), "a\nb\nc\n").unwrap();
+        let source = SourceFile::read(source_file.path()).unwrap();
+        let spec_file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(spec_file.path(), "#! format 1\n1-4\n2-6\n3-\n").unwrap();
+
+        let (_, warnings) = parse_spec_file_with_warnings(spec_file.path(), Some(&source)).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].line_no, 3);
+        assert!(matches!(
+            warnings[0].kind,
+            ParseWarningKind::BeyondSource(3)
+        ));
+    }
+
+    #[test]
+    fn dotted_ranges_are_never_legacy() {
+        let (marks, _) = parse_spec("1..65535\n").unwrap();
+        assert_eq!(read_ranges(&marks), vec![(0, 65534)]);
+    }
+
+    #[test]
+    fn note_pattern_may_contain_colons() {
+        let source_file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(source_file.path(), "use std::io;\nuse std::fmt;\n").unwrap();
+        let source = SourceFile::read(source_file.path()).unwrap();
+        let spec_file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(spec_file.path(), "[notes]\n/std::fmt/: imports: fmt\n").unwrap();
+
+        let marks = parse_spec_file(spec_file.path(), Some(&source)).unwrap();
+        let note = Note {
+            range: 1..2,
+            text: "imports: fmt".to_string(),
+        };
+        assert_eq!(marks.notes(), [note]);
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn relocate_line_moved_by_insertions() {
+        let old = lines(&["a", "b", "c"]);
+        let head = Anchor::at(&old, 1);
+        let index = AnchorIndex::new(&lines(&["x", "y", "a", "b", "c"]));
+        let spec = relocate_spec(SpecType::Line(1), head, None, &index);
+        assert!(matches!(spec, Some(SpecType::Line(3))));
+    }
+
+    #[test]
+    fn relocate_range_without_tail() {
+        let old = lines(&["a", "b", "c", "d", "e"]);
+        let (head, tail) = (Anchor::at(&old, 1), Anchor::at(&old, 3));
+        // "d" is gone, so the range keeps its length from "b"
+        let index = AnchorIndex::new(&lines(&["x", "a", "b", "c", "e"]));
+        let spec = relocate_spec(SpecType::Range(1, 4), head, tail, &index);
+        assert!(matches!(spec, Some(SpecType::Range(2, 5))));
+    }
+
+    #[test]
+    fn relocate_open_range() {
+        let old = lines(&["a", "b", "c"]);
+        let index = AnchorIndex::new(&lines(&["x", "y", "a", "b", "c"]));
+        let spec = relocate_spec(
+            SpecType::Range(1, u32::MAX),
+            Anchor::at(&old, 1),
+            None,
+            &index,
+        );
+        assert!(matches!(spec, Some(SpecType::Range(3, u32::MAX))));
+    }
+
+    #[test]
+    fn relocate_spec_without_anchors_is_kept() {
+        let index = AnchorIndex::new(&lines(&["a"]));
+        let spec = relocate_spec(SpecType::Range(4, 8), None, None, &index);
+        assert!(matches!(spec, Some(SpecType::Range(4, 8))));
+    }
+
+    fn remapped(spec: SpecType, line_map: &[Option<usize>]) -> Vec<Range<u32>> {
+        remap_spec(spec, line_map)
+            .iter()
+            .map(SpecType::range)
+            .collect()
+    }
+
+    #[test]
+    fn remap_range_with_deleted_middle_line() {
+        // the range shrinks around the deleted line
+        let line_map = [Some(0), Some(1), None, Some(2), Some(3)];
+        assert_eq!(remapped(SpecType::Range(0, 5), &line_map), vec![0..4]);
+    }
+
+    #[test]
+    fn remap_range_with_changed_middle_line() {
+        // the changed line is at 2 of the new source, and is not marked anymore
+        let line_map = [Some(0), Some(1), None, Some(3), Some(4)];
+        assert_eq!(remapped(SpecType::Range(0, 5), &line_map), vec![0..2, 3..5]);
+    }
+
+    #[test]
+    fn remap_deleted_range() {
+        let line_map = [Some(0), None, None, Some(1)];
+        assert!(remapped(SpecType::Range(1, 3), &line_map).is_empty());
+        assert!(remapped(SpecType::Line(2), &line_map).is_empty());
+        assert_eq!(remapped(SpecType::Line(3), &line_map), vec![1..2]);
+    }
+
+    #[test]
+    fn remap_open_range() {
+        let line_map = [Some(0), None, None, Some(1), Some(2)];
+        assert_eq!(
+            remapped(SpecType::Range(1, u32::MAX), &line_map),
+            vec![1..u32::MAX]
+        );
+        assert_eq!(
+            remapped(SpecType::Range(4, u32::MAX), &line_map),
+            vec![2..u32::MAX]
+        );
+        assert!(remapped(SpecType::Range(5, u32::MAX), &line_map).is_empty());
+    }
+
+    #[test]
+    fn list_spec_files_of_dot_directories() {
+        let project = tempfile::tempdir().unwrap();
+        let store = project.path().join(PROJECT_STORE_DIR);
+        fs::create_dir_all(store.join(".github/wf")).unwrap();
+        fs::write(store.join(".github/wf/ci.yml.spec"), "1\n").unwrap();
+        fs::write(store.join(".tmpAbC123"), "1\n").unwrap();
+        fs::write(store.join("README"), "shared\n").unwrap();
+
+        let spec_files = list_spec_files(&store).unwrap();
+        assert_eq!(spec_files, vec![store.join(".github/wf/ci.yml.spec")]);
+        let source_path = source_path_of_spec(&spec_files[0]).unwrap();
+        assert_eq!(source_path, Some(project.path().join(".github/wf/ci.yml")));
+    }
+
+    #[test]
+    fn split_lines_with_mixed_endings() {
+        let (lines, endings) = split_lines("a\r\nb\rc\n\r\nd");
+        assert_eq!(lines, vec!["a", "b", "c", "", "d"]);
+        assert_eq!(endings, vec!["\r\n", "\r", "\n", "\r\n", ""]);
+        assert_eq!(split_lines(""), (vec![], vec![]));
+    }
+
+    #[test]
+    fn ranges_are_written_inclusive() {
+        let mut marks = FileMarks::default();
+        if let FileMarkSpec::Partial(specs) = marks.spec_mut(Category::Read) {
+            specs.insert_range(1..4);
+            specs.insert(6);
+            specs.insert_range(9..u32::MAX);
+        }
+        let spec = format_spec(&marks, None).unwrap();
+        assert_eq!(spec, "#! format 1\n2..=4\n7\n10-\n");
+        let (marks, _) = parse_spec(&spec).unwrap();
+        assert_eq!(read_ranges(&marks), vec![(1, 4), (6, 7), (9, u32::MAX)]);
+    }
+
+    #[test]
+    fn patterns_are_written_back() {
+        let source_file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(
+            source_file.path(),
+            "use std::fmt;\n\nfn main() {\n}\nfn f() {}\n",
+        )
+        .unwrap();
+        let source = SourceFile::read(source_file.path()).unwrap();
+        let spec_file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(
+            spec_file.path(),
+            "/^fn main/+1\n/^fn gone/\n[notes]\n/^fn f/: fine\n/^fn gone/: lost\n",
+        )
+        .unwrap();
+
+        let mut marks = parse_spec_file(spec_file.path(), None).unwrap();
+        let spec = format_spec(&marks, None).unwrap();
+        // nothing matches without source, so every pattern is kept as it is
+        assert_eq!(
+            spec,
+            "#! format 1\n/^fn main/+1\n/^fn gone/\n[notes]\n/^fn f/: fine\n/^fn gone/: lost\n"
+        );
+
+        marks = parse_spec_file(spec_file.path(), Some(&source)).unwrap();
+        marks.set_note(4, "still fine".to_string());
+        let spec = format_spec(&marks, None).unwrap();
+        assert_eq!(
+            spec,
+            "#! format 1\n/^fn main/+1\n/^fn gone/\n[notes]\n/^fn f/: still fine\n/^fn gone/: lost\n"
+        );
+
+        // a pattern marking other lines than it matches is written as offsets
+        marks.unmark(3);
+        let spec = format_spec(&marks, None).unwrap();
+        assert_eq!(
+            spec,
+            "#! format 1\n3\n/^fn gone/\n[notes]\n/^fn f/: still fine\n/^fn gone/: lost\n"
+        );
+    }
+
+    fn reformat(spec: &str) -> String {
+        let (marks, _) = parse_spec(spec).unwrap();
+        format_spec(&marks, None).unwrap()
+    }
+
+    #[test]
+    fn comments_above_section_headers() {
+        let spec = "#! format 1\n# reads\n1\n\n# later\n[skip]\n3\n";
+        assert_eq!(reformat(spec), spec);
+    }
+
+    #[test]
+    fn comments_above_merged_specs() {
+        let spec = "#! format 1\n# outer\n1-5\n# inner\n3-8\n";
+        assert_eq!(reformat(spec), "#! format 1\n# outer\n# inner\n1..=7\n");
+    }
+
+    #[test]
+    fn comments_above_dropped_specs() {
+        let spec = "#! format 1\n# empty\n5-3\n7\n";
+        assert_eq!(reformat(spec), "#! format 1\n# empty\n7\n");
+    }
+
+    #[test]
+    fn comments_at_the_end() {
+        let spec = "#! format 1\n1\n# end of reads\n[notes]\n2: note\n# the end\n\n\n";
+        assert_eq!(
+            reformat(spec),
+            "#! format 1\n1\n# end of reads\n[notes]\n2: note\n# the end\n"
+        );
+    }
+
+    #[test]
+    fn comments_of_all_sections() {
+        let spec = "#! format 1\n[skip]\n# everything\n-*- all -*-\n# after\n";
+        assert_eq!(reformat(spec), spec);
+    }
+
+    #[test]
+    fn comments_move_with_sorted_specs() {
+        let spec = "#! format 1\n# B\n20-25\n# A\n3\n";
+        assert_eq!(reformat(spec), "#! format 1\n# A\n3\n# B\n20..=24\n");
     }
-    fs::write(file_path, buf)?;
-    Ok(())
 }
@@ -1,5 +1,7 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Stdout, Write};
+use std::io::{IsTerminal, Stdout, Write};
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::process::Command;
@@ -7,7 +9,7 @@ use std::time::{Duration, Instant};
 use std::{env, error, fs, io};
 
 use anyhow::Context;
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use crossterm::event::KeyModifiers;
 use crossterm::{
     event::{self, Event, KeyCode},
@@ -15,39 +17,72 @@ use crossterm::{
     ExecutableCommand,
 };
 use ratatui::{prelude::*, text::Line, widgets::*};
+use rayon::prelude::*;
 use termcolor::{BufferWriter, ColorSpec, WriteColor};
 use tui_input::backend::crossterm::EventHandler;
 use tui_input::Input;
 
-use marks::FileMarkSpec;
+use marks::{
+    Category, DirectoryStatus, FileMarkSpec, FileMarkStatus, FileMarks, LineCountCache, ParseError,
+    ParseWarning, SourceFile, SourceFilter, StatusTree,
+};
+
+// colors of line number and line
+fn category_colors(category: Category) -> (Color, Color) {
+    match category {
+        Category::Read => (Color::Cyan, Color::Green),
+        Category::FollowUp => (Color::Yellow, Color::Yellow),
+        Category::Suspicious => (Color::Magenta, Color::Red),
+        Category::Skip => (Color::Blue, Color::DarkGray),
+    }
+}
+
+fn category_tcolors(category: Category) -> (termcolor::Color, termcolor::Color) {
+    use termcolor::Color as tColor;
+    match category {
+        Category::Read => (tColor::Cyan, tColor::Green),
+        Category::FollowUp => (tColor::Yellow, tColor::Yellow),
+        Category::Suspicious => (tColor::Magenta, tColor::Red),
+        Category::Skip => (tColor::Blue, tColor::Ansi256(8)),
+    }
+}
+
+// separator between line number and line, which marks lines with a note
+fn gutter(file_marks: &FileMarks, line_offset: usize) -> &'static str {
+    match file_marks.note_at(line_offset as u32) {
+        Some(_) => "*",
+        None => "|",
+    }
+}
 
-fn print_file(file: &File, spec: &FileMarkSpec) -> anyhow::Result<()> {
-    use termcolor::{Color as tColor, ColorChoice};
+fn print_file(source: &SourceFile, file_marks: &FileMarks) -> anyhow::Result<()> {
+    use termcolor::ColorChoice;
     let writer = BufferWriter::stdout(ColorChoice::Always);
     let mut buffer = writer.buffer();
 
-    let mut line_offset = 0u16;
-    let mut read_buf = String::new();
-    let mut reader = BufReader::new(file);
-    while reader.read_line(&mut read_buf)? > 0 {
-        let line = read_buf.trim_end_matches('\n');
+    for (line_offset, line) in source.lines.iter().enumerate() {
         let line_no = line_offset + 1;
+        let gutter = gutter(file_marks, line_offset);
+        // keep CRLF, but a lone CR would overwrite the line on terminals
+        let ending = match source.line_ending(line_offset) {
+            "\r\n" => "\r\n",
+            _ => "\n",
+        };
 
         // color print
-        if spec.match_line_offset(line_offset) {
-            buffer.set_color(ColorSpec::new().set_fg(Some(tColor::Cyan)))?;
+        if let Some(category) = file_marks.category_of(line_offset as u32) {
+            let (line_no_color, line_color) = category_tcolors(category);
+            buffer.set_color(ColorSpec::new().set_fg(Some(line_no_color)))?;
             write!(&mut buffer, "{:>4}", line_no)?;
             buffer.reset()?;
-            write!(&mut buffer, "|")?;
-            buffer.set_color(ColorSpec::new().set_fg(Some(tColor::Green)))?;
-            writeln!(&mut buffer, "{}", line)?;
+            write!(&mut buffer, "{}", gutter)?;
+            buffer.set_color(ColorSpec::new().set_fg(Some(line_color)))?;
+            write!(&mut buffer, "{}", line)?;
             buffer.reset()?;
+            write!(&mut buffer, "{}", ending)?;
         } else {
-            writeln!(&mut buffer, "{:>4}|{}", line_no, line)?;
+            write!(&mut buffer, "{:>4}{}{}{}", line_no, gutter, line, ending)?;
         }
-
-        line_offset += 1;
-        read_buf.clear();
     }
     writer.print(&buffer)?;
     Ok(())
@@ -56,6 +91,9 @@ fn print_file(file: &File, spec: &FileMarkSpec) -> anyhow::Result<()> {
 #[derive(Parser)]
 #[command(author, version, about, long_about=None)]
 #[command(propagate_version = true)]
+#[command(
+    after_help = "Sources are decoded as UTF-8, or with the encoding named by $MARKS_ENCODING (e.g. latin1)."
+)]
 struct MarksCommands {
     #[command(subcommand)]
     commands: Commands,
@@ -75,6 +113,36 @@ enum Commands {
     /// Show status of all sources
     Status(StatusCommand),
 
+    /// Validate spec files of sources, and fail when too few lines are marked
+    Check(CheckCommand),
+
+    /// List sources which have spec files
+    Ls(LsCommand),
+
+    /// List spec files of deleted sources or without marks, and remove them with --prune
+    Gc(GcCommand),
+
+    /// Move marks of a source to another source, e.g. after renaming it
+    Mv(MvCommand),
+
+    /// Copy marks of a source to another source
+    Cp(CpCommand),
+
+    /// Move marks of all sources after moving the checkout from OLD_ROOT to NEW_ROOT
+    Relocate(RelocateCommand),
+
+    /// Create project-local store to share marks with the project
+    Init(InitCommand),
+
+    /// Combine spec of source with other spec files
+    Combine(CombineCommand),
+
+    /// Invert spec of source to unmarked lines
+    Invert(InvertCommand),
+
+    /// List notes of sources
+    Notes(NotesCommand),
+
     /// Debug command
     Debug(DebugCommand),
 }
@@ -88,14 +156,20 @@ impl PrintCommand {
     fn run(&self) -> anyhow::Result<()> {
         let source_path = &self.source;
         let spec_file_path = marks::get_spec_file_path(source_path);
-        marks::touch_file(&spec_file_path)?;
 
         // parse spec file
-        let spec = marks::parse_spec_file(&spec_file_path)?;
+        let source = SourceFile::read(source_path)?;
+        let (file_marks, warnings) = if spec_file_path.exists() {
+            marks::parse_spec_file_with_warnings(&spec_file_path, Some(&source))?
+        } else {
+            Default::default()
+        };
+        for warning in warnings {
+            eprintln!("warning: {}", warning);
+        }
 
         // print source file with color
-        let source_file = File::open(source_path)?;
-        print_file(&source_file, &spec)?;
+        print_file(&source, &file_marks)?;
 
         Ok(())
     }
@@ -105,11 +179,16 @@ fn edit_with_editor<P: AsRef<Path>>(file_path: P) -> anyhow::Result<()> {
     let file_path = file_path.as_ref();
 
     let editor = env::var("EDITOR").context("EDITOR variable not found")?;
+    // e.g. "code --wait"
+    let editor = shell_words::split(&editor).context("failed to parse EDITOR")?;
+    let (program, args) = editor.split_first().context("EDITOR is empty")?;
 
-    let mut command = Command::new(editor);
-    command.arg(file_path);
-    let child = command.spawn()?;
-    child.wait_with_output()?;
+    let mut command = Command::new(program);
+    command.args(args).arg(file_path);
+    let status = command
+        .status()
+        .with_context(|| format!("failed to run {}", program))?;
+    anyhow::ensure!(status.success(), "{} exited with {}", program, status);
 
     Ok(())
 }
@@ -125,64 +204,142 @@ struct EditCommand {
     all: bool,
 }
 
+// comment on top of the spec being edited, replaced by `edit_error_header` after an error
+const EDIT_HELP: &str = "\
+# Lines are 1-based, and the end of `A-B` and `A..B` is exclusive.
+#   N         line N
+#   A-B, A..B lines A to B-1
+#   A..=B     lines A to B
+#   A-, -B    lines from A to the end, lines before B
+#   /re/+N    first line matching the regex re, and N lines after it
+#   -*- all -*-
+";
+
+// comment on top of a spec which failed to parse, with the line of the error in the buffer
+fn edit_error_header(error: &ParseError) -> String {
+    // lines of this header
+    const LINE_NO: usize = 3;
+    format!(
+        "# error: line {}: {}: {:?}\n\
+         # Fix the spec and save it, or quit without saving to give up.\n\
+         #\n",
+        error.line_no + LINE_NO,
+        error.kind,
+        error.text
+    )
+}
+
 impl EditCommand {
     fn run(&self) -> anyhow::Result<()> {
-        let spec_file_dir = marks::get_spec_file_dir();
         let spec_file_path = marks::get_spec_file_path(&self.source);
-        marks::touch_file(&spec_file_path)?;
+        let spec_file_dir = spec_file_path
+            .parent()
+            .context("failed to get parent directory")?;
 
         if self.reset {
-            fs::remove_file(&spec_file_path)?;
-            return Ok(());
-        }
-
-        if self.all {
             if spec_file_path.exists() {
-                fs::remove_file(&spec_file_path)?;
+                marks::remove_spec_file(&spec_file_path)?;
             }
-
-            fs::write(&spec_file_path, format!("{}\n", marks::ALL_MAGIC))?;
             return Ok(());
         }
 
-        let mut tmp = tempfile::NamedTempFile::new_in(&spec_file_dir)?;
-        let mut spec_file = File::open(&spec_file_path)?;
-        io::copy(&mut spec_file, &mut tmp)?;
+        // anchors are resolved here and written back after editing, so the user only sees offsets
+        let source = SourceFile::read(&self.source)?;
+        let mut file_marks = marks::read_marks(&spec_file_path, Some(&source))?;
+        fs::create_dir_all(spec_file_dir)?;
 
-        edit_with_editor(tmp.path())?;
+        if self.all {
+            file_marks.set_spec(Category::Read, FileMarkSpec::All);
+        } else {
+            let tmp = tempfile::NamedTempFile::new_in(spec_file_dir)?;
+            let mut header = EDIT_HELP.to_string();
+            let mut buf = header.clone() + &marks::format_spec(&file_marks, None)?;
+            // the spec is edited until it parses
+            let warnings = loop {
+                fs::write(tmp.path(), &buf)?;
+                edit_with_editor(tmp.path())?;
+                let edited = fs::read_to_string(tmp.path())?;
+
+                // the header is not a comment of the user to keep in the spec file
+                let spec = edited.strip_prefix(&header).unwrap_or(&edited).to_string();
+                fs::write(tmp.path(), &spec)?;
+                let err = match marks::parse_spec_file_with_warnings(tmp.path(), Some(&source)) {
+                    Ok((marks, warnings)) => {
+                        file_marks = marks;
+                        break warnings;
+                    }
+                    Err(err) => err,
+                };
+                let Some(error) = err.downcast_ref::<ParseError>() else {
+                    return Err(err);
+                };
 
-        let mut spec = marks::parse_spec_file(tmp.path())?;
-        spec.optimize();
+                // quitting without saving the error leaves the buffer as it was
+                if header != EDIT_HELP && edited == buf {
+                    let (_, path) = tmp.keep()?;
+                    anyhow::bail!("{}\nthe edited spec is kept in {}", error, path.display());
+                }
+                eprintln!("error: {}", error.kind);
+                header = edit_error_header(error);
+                buf = header.clone() + &spec;
+            };
+            for mut warning in warnings {
+                // the temporary file replaces the spec file
+                warning.path = spec_file_path.clone();
+                eprintln!("warning: {}", warning);
+            }
+        }
 
-        let tmp = tempfile::NamedTempFile::new_in(&spec_file_dir)?;
-        marks::write_spec_file(tmp.path(), &spec)?;
+        let tmp = tempfile::NamedTempFile::new_in(spec_file_dir)?;
+        marks::write_spec_file(tmp.path(), &file_marks, Some(&source))?;
 
         fs::rename(tmp.path(), &spec_file_path)?;
         Ok(())
     }
 }
 
+// category marked by a key in the viewer
+fn category_by_key(key: char) -> Category {
+    match key {
+        'f' => Category::FollowUp,
+        's' => Category::Suspicious,
+        'x' => Category::Skip,
+        _ => Category::Read,
+    }
+}
+
 #[derive(Debug)]
 enum InputMode {
     Normal,
     Editing,
+    Note,
+}
+
+impl InputMode {
+    fn palette_prefix(&self) -> &'static str {
+        match self {
+            InputMode::Normal => ":",
+            InputMode::Editing => "/",
+            InputMode::Note => "note: ",
+        }
+    }
 }
 
 struct ViewApp {
     spec_file_path: PathBuf,
-    spec: FileMarkSpec,
+    file_marks: FileMarks,
 
-    source_lines: Vec<String>,
-    source_line_len: u16,
+    source: SourceFile,
+    source_line_len: usize,
 
     // top of the screen
     // 0-index
-    offset: u16,
+    offset: usize,
     // 0-index
-    cursor_line_offset: u16,
+    cursor_line_offset: usize,
 
-    source_view_padding_height: u16,
-    source_view_height: u16,
+    source_view_padding_height: usize,
+    source_view_height: usize,
 
     input_mode: InputMode,
     input: Input,
@@ -191,18 +348,16 @@ struct ViewApp {
 }
 
 impl ViewApp {
-    fn new(source_file_path: PathBuf) -> Self {
+    fn new(source_file_path: PathBuf) -> anyhow::Result<Self> {
         let spec_file_path = marks::get_spec_file_path(&source_file_path);
-        marks::touch_file(&spec_file_path).expect("failed to touch spec file");
 
-        let source_lines =
-            Self::read_source_by_line(&source_file_path).expect("failed to read source file");
-        let spec = marks::parse_spec_file(&spec_file_path).expect("failed to parse spec file");
-        let source_line_len = source_lines.len() as u16;
-        Self {
+        let source = SourceFile::read(&source_file_path)?;
+        let file_marks = marks::read_marks(&spec_file_path, Some(&source))?;
+        let source_line_len = source.lines.len();
+        Ok(Self {
             spec_file_path,
-            spec,
-            source_lines,
+            file_marks,
+            source,
             source_line_len,
 
             offset: 0,
@@ -214,7 +369,7 @@ impl ViewApp {
             input: Input::default(),
 
             grep_text: None,
-        }
+        })
     }
 
     fn update_offset(&mut self) {
@@ -231,23 +386,24 @@ impl ViewApp {
         }
     }
 
-    fn jump_cursor(&mut self, index: u16) {
+    fn jump_cursor(&mut self, index: usize) {
         self.cursor_line_offset = index.min(self.source_line_len.saturating_sub(1));
         self.update_offset();
     }
 
-    fn inc_cursor(&mut self, count: u16) {
+    fn inc_cursor(&mut self, count: usize) {
         self.jump_cursor(self.cursor_line_offset.saturating_add(count));
     }
 
-    fn dec_cursor(&mut self, count: u16) {
+    fn dec_cursor(&mut self, count: usize) {
         self.jump_cursor(self.cursor_line_offset.saturating_sub(count));
     }
 
     fn run(source_file_path: PathBuf) -> anyhow::Result<()> {
+        // errors are shown before the terminal is taken over
+        let mut app = Self::new(source_file_path)?;
         let mut terminal = init_terminal()?;
         let mut last_tick = Instant::now();
-        let mut app = Self::new(source_file_path);
         let tick_rate = Duration::from_millis(16);
         loop {
             let _ = terminal.draw(|frame| app.ui(frame).unwrap());
@@ -257,6 +413,7 @@ impl ViewApp {
                 let handle_result = match app.input_mode {
                     InputMode::Normal => app.normal_mode_handler()?,
                     InputMode::Editing => app.editing_mode_handler()?,
+                    InputMode::Note => app.note_mode_handler()?,
                 };
                 if handle_result.is_none() {
                     break;
@@ -268,8 +425,7 @@ impl ViewApp {
                 last_tick = Instant::now();
             }
         }
-        app.spec.optimize();
-        marks::write_spec_file(app.spec_file_path, &app.spec)?;
+        app.save()?;
 
         restore_terminal()?;
         Ok(())
@@ -287,35 +443,35 @@ impl ViewApp {
         }
     }
 
-    fn prev_matched_index(&self, needle: &str) -> Option<u16> {
+    fn prev_matched_index(&self, needle: &str) -> Option<usize> {
         if self.cursor_line_offset == 0 {
             return None;
         }
-        let offset = self.cursor_line_offset as usize;
-        for (idx, line) in self.source_lines[..offset].iter().rev().enumerate() {
+        let offset = self.cursor_line_offset;
+        for (idx, line) in self.source.lines[..offset].iter().rev().enumerate() {
             if line.contains(needle) {
-                return Some((offset - idx - 1) as u16);
+                return Some(offset - idx - 1);
             }
         }
         None
     }
 
-    fn next_matched_index(&self, needle: &str) -> Option<u16> {
+    fn next_matched_index(&self, needle: &str) -> Option<usize> {
         if self.cursor_line_offset + 1 >= self.source_line_len {
             return None;
         }
 
-        let start_offset = self.cursor_line_offset as usize + 1;
-        for (idx, line) in self.source_lines[start_offset..].iter().enumerate() {
+        let start_offset = self.cursor_line_offset + 1;
+        for (idx, line) in self.source.lines[start_offset..].iter().enumerate() {
             if line.contains(needle) {
-                return Some((start_offset + idx) as u16);
+                return Some(start_offset + idx);
             }
         }
         None
     }
 
     fn current_line_contains(&self, needle: &str) -> bool {
-        self.source_lines[self.cursor_line_offset as usize].contains(needle)
+        self.source.lines[self.cursor_line_offset].contains(needle)
     }
 
     fn normal_mode_handler(&mut self) -> anyhow::Result<Option<()>> {
@@ -344,29 +500,36 @@ impl ViewApp {
                 KeyCode::Char('u') if key.modifiers.contains(KeyModifiers::CONTROL) => {
                     self.dec_cursor(10)
                 }
-                KeyCode::Char('m') => {
-                    self.spec.add(self.cursor_line_offset);
+                KeyCode::Char(c @ ('m' | 'f' | 's' | 'x')) => {
+                    let category = category_by_key(c);
+                    self.file_marks
+                        .mark(self.cursor_line_offset as u32, category);
                     self.inc_cursor(1);
                 }
-                KeyCode::Char('M') => {
-                    self.spec.add(self.cursor_line_offset);
+                KeyCode::Char(c @ ('M' | 'F' | 'S' | 'X')) => {
+                    let category = category_by_key(c.to_ascii_lowercase());
+                    self.file_marks
+                        .mark(self.cursor_line_offset as u32, category);
                     self.dec_cursor(1);
                 }
                 KeyCode::Char('u') => {
-                    self.spec.remove(self.cursor_line_offset);
+                    self.file_marks.unmark(self.cursor_line_offset as u32);
                     self.inc_cursor(1);
                 }
                 KeyCode::Char('U') => {
-                    self.spec.remove(self.cursor_line_offset);
+                    self.file_marks.unmark(self.cursor_line_offset as u32);
                     self.dec_cursor(1);
                 }
-                KeyCode::Char('o') => {
-                    self.spec.optimize();
-                    marks::write_spec_file(&self.spec_file_path, &self.spec)?;
-                }
+                KeyCode::Char('o') => self.save()?,
                 KeyCode::Char('/') => {
                     self.input_mode = InputMode::Editing;
                 }
+                KeyCode::Char('a') => {
+                    let note = self.file_marks.note_at(self.cursor_line_offset as u32);
+                    let text = note.map(|note| note.text.clone()).unwrap_or_default();
+                    self.input = Input::new(text);
+                    self.input_mode = InputMode::Note;
+                }
                 _ => {}
             }
         }
@@ -401,8 +564,41 @@ impl ViewApp {
         Ok(Some(()))
     }
 
+    fn note_mode_handler(&mut self) -> anyhow::Result<Option<()>> {
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Enter => {
+                    // empty note removes the note
+                    let text = self.input.value().trim().to_string();
+                    self.file_marks
+                        .set_note(self.cursor_line_offset as u32, text);
+
+                    self.input.reset();
+                    self.input_mode = InputMode::Normal;
+                }
+                KeyCode::Esc => {
+                    // cancel
+                    self.input.reset();
+                    self.input_mode = InputMode::Normal;
+                }
+                _ => {
+                    self.input.handle_event(&Event::Key(key));
+                }
+            }
+        }
+        Ok(Some(()))
+    }
+
     fn on_tick(&mut self) {}
 
+    fn save(&self) -> anyhow::Result<()> {
+        // a source without marks is not given a spec file
+        if self.file_marks.is_empty() && !self.spec_file_path.exists() {
+            return Ok(());
+        }
+        marks::write_spec_file(&self.spec_file_path, &self.file_marks, Some(&self.source))
+    }
+
     fn ui(&mut self, frame: &mut Frame) -> anyhow::Result<()> {
         let chunks = Layout::default()
             .direction(Direction::Vertical)
@@ -410,15 +606,16 @@ impl ViewApp {
             .split(frame.size());
 
         let rect = chunks[0];
-        self.source_view_height = rect.height;
+        self.source_view_height = rect.height as usize;
         frame.render_widget(self.paragraph(rect)?, rect);
 
         let rect = chunks[1];
         frame.render_widget(self.command_palette(rect), rect);
-        if matches!(self.input_mode, InputMode::Editing) {
+        if !matches!(self.input_mode, InputMode::Normal) {
             let scroll = self.input.visual_scroll(rect.width as usize);
+            let prefix_width = self.input_mode.palette_prefix().len() as u16;
             frame.set_cursor(
-                rect.x + (self.input.visual_cursor().max(scroll) - scroll) as u16 + 1,
+                rect.x + (self.input.visual_cursor().max(scroll) - scroll) as u16 + prefix_width,
                 rect.y,
             );
         }
@@ -429,26 +626,28 @@ impl ViewApp {
         let width = window_size.width;
         let scroll = self.input.visual_scroll(width as usize);
 
-        let palette = match self.input_mode {
-            InputMode::Normal => format!(":{}", self.input.value()),
-            InputMode::Editing => format!("/{}", self.input.value()),
+        let note = self.file_marks.note_at(self.cursor_line_offset as u32);
+        let palette = match (&self.input_mode, note) {
+            // show note of the cursor line
+            (InputMode::Normal, Some(note)) => format!("note: {}", note.text),
+            (mode, _) => format!("{}{}", mode.palette_prefix(), self.input.value()),
         };
 
         Paragraph::new(palette).scroll((0, scroll as u16))
     }
 
     fn paragraph(&self, window_size: Rect) -> anyhow::Result<impl Widget + '_> {
-        let offset = self.offset as usize;
+        let offset = self.offset;
         let (height, width) = (window_size.height as usize, window_size.width);
 
         let text = self.mark_lines_by_spec(offset..(offset + height), width);
         Ok(Paragraph::new(text))
     }
 
-    fn mark_lines_by_spec(&self, idx_range: Range<usize>, window_width: u16) -> Vec<Line> {
+    fn mark_lines_by_spec(&self, idx_range: Range<usize>, window_width: u16) -> Vec<Line<'_>> {
         let start_offset = idx_range.start;
-        let idx_range = idx_range.start..idx_range.end.min(self.source_line_len as usize);
-        self.source_lines[idx_range]
+        let idx_range = idx_range.start..idx_range.end.min(self.source_line_len);
+        self.source.lines[idx_range]
             .iter()
             .enumerate()
             .map(|(i, line)| self.mark_line_by_spec(start_offset + i, line, window_width))
@@ -460,16 +659,18 @@ impl ViewApp {
         line_offset: usize,
         line: &'a str,
         window_width: u16,
-    ) -> Line {
+    ) -> Line<'a> {
         let mut line_no_style = Style::default();
         let mut style = Style::default();
-        if line_offset == self.cursor_line_offset as usize {
+        if line_offset == self.cursor_line_offset {
             style = style.underlined();
         }
-        let line_matched = self.spec.match_line_offset(line_offset as u16);
-        if line_matched {
-            line_no_style = line_no_style.fg(Color::Cyan);
-            style = style.fg(Color::Green);
+        let category = self.file_marks.category_of(line_offset as u32);
+        let line_matched = category.is_some();
+        if let Some(category) = category {
+            let (line_no_color, line_color) = category_colors(category);
+            line_no_style = line_no_style.fg(line_no_color);
+            style = style.fg(line_color);
         }
 
         // line_no length and padding length = 4 + 1
@@ -479,7 +680,10 @@ impl ViewApp {
             format!("{:>4}", line_offset + 1),
             line_no_style,
         ));
-        spans.push(Span::styled("|", Style::default()));
+        spans.push(Span::styled(
+            gutter(&self.file_marks, line_offset),
+            Style::default(),
+        ));
 
         let mut cursor = 0;
         if let Some(grep_text) = self.grep_text.as_ref() {
@@ -520,21 +724,6 @@ impl ViewApp {
         ));
         Line::from(spans)
     }
-
-    fn read_source_by_line<P: AsRef<Path>>(source_file_path: P) -> anyhow::Result<Vec<String>> {
-        let source_file = File::open(source_file_path)?;
-        let mut reader = BufReader::new(source_file);
-        let mut buf = String::new();
-
-        let mut source_lines = vec![];
-        while reader.read_line(&mut buf)? > 0 {
-            let line = buf.trim_end_matches('\n').to_string();
-            source_lines.push(line);
-            buf.clear();
-        }
-
-        Ok(source_lines)
-    }
 }
 
 #[derive(Args, Debug)]
@@ -553,26 +742,933 @@ impl ViewCommand {
 #[derive(Args, Debug)]
 struct StatusCommand {
     sources: Vec<String>,
+
+    /// Only count sources of directories matching the glob, e.g. '*.rs'
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Skip sources of directories matching the glob, e.g. 'tests/**'
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t)]
+    format: StatusFormat,
+
+    /// Add a subtotal of each subdirectory of directories
+    #[arg(long)]
+    per_directory: bool,
+
+    /// Show directories as trees with progress bars
+    #[arg(long, conflicts_with_all = ["format", "per_directory"])]
+    tree: bool,
+
+    /// Order of children in trees
+    #[arg(long, value_enum, default_value_t, requires = "tree")]
+    sort: TreeSort,
+
+    /// Show trees down to DEPTH levels below the given directories
+    #[arg(long, requires = "tree")]
+    depth: Option<usize>,
+
+    /// Count lines of every source instead of using the cached counts
+    #[arg(long)]
+    no_cache: bool,
 }
 
 impl StatusCommand {
     fn run(&self) -> anyhow::Result<()> {
+        let filter = SourceFilter {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+        };
+        let cache = if self.no_cache {
+            LineCountCache::default()
+        } else {
+            LineCountCache::open(marks::get_line_count_cache_path())
+        };
+
+        let mut rows = vec![];
+        // sources given more than once, e.g. in a directory and by themselves, count once
+        let mut totals = BTreeMap::new();
         for source in &self.sources {
             let file_path = PathBuf::from(source);
+            if !file_path.is_dir() {
+                // skipped like binary files inside directories
+                if SourceFile::is_binary(&file_path)? {
+                    eprintln!("{}: skipping binary file", source);
+                    continue;
+                }
+                let status = match marks::file_status(&file_path) {
+                    Ok(status) => status,
+                    // counted as unmarked like `check` does
+                    Err(err) => {
+                        eprintln!("error: {}", err.downcast::<ParseError>()?);
+                        FileMarkStatus {
+                            line_no: cache.line_count(&file_path)?,
+                            ..Default::default()
+                        }
+                    }
+                };
+                let status = DirectoryStatus::from(status);
+                if self.tree {
+                    let tree = StatusTree {
+                        path: file_path,
+                        status,
+                        children: Default::default(),
+                    };
+                    print_status_tree(&tree, source, self.sort, self.depth)?;
+                    continue;
+                }
+                totals.insert(marks::canonical_path(&file_path)?, status);
+                rows.push(StatusRow::new(source, StatusKind::File, status));
+                continue;
+            }
+
+            let (tree, errors) = marks::status_tree(&file_path, &filter, &cache)?;
+            for error in errors {
+                eprintln!("error: {}", error);
+            }
+            if self.tree {
+                print_status_tree(&tree, source, self.sort, self.depth)?;
+                continue;
+            }
+            collect_source_statuses(&tree, &mut totals)?;
+            rows.push(StatusRow::new(source, StatusKind::Directory, tree.status));
+            if self.per_directory {
+                push_subdirectory_rows(&mut rows, &tree);
+            }
+        }
+        cache.save()?;
+        if self.tree {
+            return Ok(());
+        }
+        let mut total = DirectoryStatus::default();
+        for status in totals.values() {
+            total.add(status);
+        }
+        rows.push(StatusRow::new("total", StatusKind::Total, total));
+
+        match self.format {
+            StatusFormat::Table => print_status_table(&rows),
+            StatusFormat::Json => print_status_json(&rows)?,
+            StatusFormat::Csv => print_status_csv(&rows),
+        }
+        Ok(())
+    }
+}
+
+// subtotal rows of the subdirectories of a tree, in path order
+fn push_subdirectory_rows(rows: &mut Vec<StatusRow>, tree: &StatusTree) {
+    for child in tree.children.values() {
+        if child.children.is_empty() {
+            continue;
+        }
+        let path = child.path.display().to_string();
+        rows.push(StatusRow::new(&path, StatusKind::Directory, child.status));
+        push_subdirectory_rows(rows, child);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum TreeSort {
+    /// By file name
+    #[default]
+    Name,
+    /// Lowest percentage of marked lines first
+    LeastRead,
+}
+
+// width of progress bars in characters
+const PROGRESS_BAR_WIDTH: usize = 20;
+
+fn print_status_tree(
+    tree: &StatusTree,
+    name: &str,
+    sort: TreeSort,
+    depth: Option<usize>,
+) -> anyhow::Result<()> {
+    use termcolor::ColorChoice;
+    let choice = if io::stdout().is_terminal() {
+        ColorChoice::Auto
+    } else {
+        ColorChoice::Never
+    };
+    let writer = BufferWriter::stdout(choice);
+    let mut buffer = writer.buffer();
+
+    // (prefix + name, node) in display order
+    let mut lines = vec![(name.to_string(), tree)];
+    collect_tree_lines(&mut lines, tree, "", sort, depth.unwrap_or(usize::MAX));
+    let width = lines
+        .iter()
+        .map(|(label, _)| label.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    for (label, node) in lines {
+        let status = &node.status;
+        let pad = width - label.chars().count();
+        let counts = format!("{}/{}", status.marked, status.line_no);
+        write!(
+            &mut buffer,
+            "{}{}  {:>13}  {:>5.1}% ",
+            label,
+            " ".repeat(pad),
+            counts,
+            status.percent()
+        )?;
+
+        let filled = (status.percent() / 100.0 * PROGRESS_BAR_WIDTH as f64).round() as usize;
+        write!(&mut buffer, "[")?;
+        buffer.set_color(ColorSpec::new().set_fg(Some(progress_color(status.percent()))))?;
+        write!(&mut buffer, "{}", "#".repeat(filled))?;
+        buffer.reset()?;
+        writeln!(&mut buffer, "{}]", "-".repeat(PROGRESS_BAR_WIDTH - filled))?;
+    }
+    writer.print(&buffer)?;
+    Ok(())
+}
+
+fn collect_tree_lines<'a>(
+    lines: &mut Vec<(String, &'a StatusTree)>,
+    tree: &'a StatusTree,
+    prefix: &str,
+    sort: TreeSort,
+    depth: usize,
+) {
+    if depth == 0 {
+        return;
+    }
+    let mut children: Vec<(&OsString, &StatusTree)> = tree.children.iter().collect();
+    if let TreeSort::LeastRead = sort {
+        children.sort_by(|(_, a), (_, b)| a.status.percent().total_cmp(&b.status.percent()));
+    }
+
+    for (i, (name, child)) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        let (branch, indent) = if last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        let mut label = format!("{}{}{}", prefix, branch, name.to_string_lossy());
+        if !child.children.is_empty() {
+            label.push('/');
+        }
+        lines.push((label, child));
+        collect_tree_lines(
+            lines,
+            child,
+            &format!("{}{}", prefix, indent),
+            sort,
+            depth - 1,
+        );
+    }
+}
+
+fn progress_color(percent: f64) -> termcolor::Color {
+    if percent >= 80.0 {
+        termcolor::Color::Green
+    } else if percent >= 30.0 {
+        termcolor::Color::Yellow
+    } else {
+        termcolor::Color::Red
+    }
+}
+
+#[derive(Clone, Debug, Default, ValueEnum)]
+enum StatusFormat {
+    /// Aligned columns
+    #[default]
+    Table,
+    /// Object with a "sources" array and a "total" object
+    Json,
+    /// Comma-separated values with a header
+    Csv,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum StatusKind {
+    File,
+    Directory,
+    Total,
+}
+
+impl StatusKind {
+    fn name(&self) -> &'static str {
+        match self {
+            StatusKind::File => "file",
+            StatusKind::Directory => "directory",
+            StatusKind::Total => "total",
+        }
+    }
+}
+
+struct StatusRow {
+    path: String,
+    kind: StatusKind,
+    status: DirectoryStatus,
+}
+
+impl StatusRow {
+    fn new(path: &str, kind: StatusKind, status: DirectoryStatus) -> Self {
+        Self {
+            path: path.to_string(),
+            kind,
+            status,
+        }
+    }
+}
+
+// as wide as MARKED and LINES
+fn category_column_width(category: Category) -> usize {
+    category.name().len().max(8)
+}
+
+// status of each source of a tree by canonical path
+fn collect_source_statuses(
+    tree: &StatusTree,
+    statuses: &mut BTreeMap<PathBuf, DirectoryStatus>,
+) -> anyhow::Result<()> {
+    // directories without sources have no children either
+    if tree.children.is_empty() && tree.status.files > 0 {
+        statuses.insert(marks::canonical_path(&tree.path)?, tree.status);
+    }
+    for child in tree.children.values() {
+        collect_source_statuses(child, statuses)?;
+    }
+    Ok(())
+}
+
+fn print_status_table(rows: &[StatusRow]) {
+    let width = rows.iter().map(|row| row.path.len()).max().unwrap_or(0);
+    print!(
+        "{:<width$}  {:>8}  {:>8}  {:>7}  {:>11}",
+        "PATH", "MARKED", "LINES", "PERCENT", "FILES"
+    );
+    for category in Category::ALL {
+        let width = category_column_width(category);
+        print!("  {:>width$}", category.name().to_uppercase());
+    }
+    println!();
+
+    for row in rows {
+        let status = &row.status;
+        let files = format!("{}/{}", status.marked_files, status.files);
+        print!(
+            "{:<width$}  {:>8}  {:>8}  {:>6.1}%  {:>11}",
+            row.path,
+            status.marked,
+            status.line_no,
+            status.percent(),
+            files
+        );
+        for category in Category::ALL {
+            let width = category_column_width(category);
+            print!("  {:>width$}", status.by_category[category as usize]);
+        }
+        println!();
+    }
+}
+
+fn print_status_json(rows: &[StatusRow]) -> anyhow::Result<()> {
+    let to_json = |row: &StatusRow| {
+        let status = &row.status;
+        let categories: serde_json::Map<String, serde_json::Value> = Category::ALL
+            .into_iter()
+            .map(|category| {
+                let count = status.by_category[category as usize];
+                (category.name().to_string(), count.into())
+            })
+            .collect();
+        serde_json::json!({
+            "path": row.path,
+            "kind": row.kind.name(),
+            "marked": status.marked,
+            "lines": status.line_no,
+            "percent": status.percent(),
+            "categories": categories,
+            "files": status.files,
+            "marked_files": status.marked_files,
+        })
+    };
+
+    let (total, sources) = rows.split_last().context("no total")?;
+    let json = serde_json::json!({
+        "sources": sources.iter().map(to_json).collect::<Vec<_>>(),
+        "total": to_json(total),
+    });
+    println!("{}", serde_json::to_string_pretty(&json)?);
+    Ok(())
+}
+
+fn print_status_csv(rows: &[StatusRow]) {
+    let categories: Vec<&str> = Category::ALL.iter().map(Category::name).collect();
+    println!(
+        "path,kind,marked,lines,percent,{},files,marked_files",
+        categories.join(",")
+    );
+    for row in rows {
+        let status = &row.status;
+        let counts: Vec<String> = status.by_category.iter().map(u64::to_string).collect();
+        println!(
+            "{},{},{},{},{:.1},{},{},{}",
+            csv_field(&row.path),
+            row.kind.name(),
+            status.marked,
+            status.line_no,
+            status.percent(),
+            counts.join(","),
+            status.files,
+            status.marked_files
+        );
+    }
+}
+
+// quote a field containing separators or quotes (RFC 4180)
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[derive(Args, Debug)]
+struct CheckCommand {
+    /// Sources or directories of sources to check
+    #[arg(default_value = ".")]
+    sources: Vec<String>,
+
+    /// Fail if less than PERCENT of all lines are marked
+    #[arg(long, value_name = "PERCENT")]
+    min_percent: Option<f64>,
 
-            let status = if file_path.is_dir() {
-                marks::directory_status(&file_path)?
+    /// Only check sources of directories matching the glob, e.g. '*.rs'
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Skip sources of directories matching the glob, e.g. 'tests/**'
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+}
+
+impl CheckCommand {
+    fn run(&self) -> anyhow::Result<()> {
+        let filter = SourceFilter {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+        };
+        let mut sources = vec![];
+        for source in &self.sources {
+            let path = PathBuf::from(source);
+            if path.is_dir() {
+                sources.extend(marks::walk_sources(&path, &filter)?);
             } else {
-                marks::file_status(&file_path)?
+                sources.push(path);
+            }
+        }
+
+        let cache = LineCountCache::open(marks::get_line_count_cache_path());
+        let results: Vec<_> = sources
+            .par_iter()
+            .map(|source| {
+                check_source(source, &cache).with_context(|| source.display().to_string())
+            })
+            .collect();
+        cache.save()?;
+
+        let mut total = DirectoryStatus::default();
+        // invalid spec files, and sources or spec files which failed to be read
+        let (mut invalid, mut unreadable, mut warnings) = (0, 0, 0);
+        for (source, result) in sources.iter().zip(results) {
+            let (status, source_warnings) = match result {
+                Ok(result) => result,
+                Err(err) => {
+                    eprintln!("error: {:#}", err);
+                    match err.downcast_ref::<ParseError>() {
+                        Some(_) => invalid += 1,
+                        None => unreadable += 1,
+                    }
+                    // lines of a source with an invalid spec count as unmarked
+                    let line_no = cache.line_count(source).unwrap_or(0);
+                    total.add_file(&FileMarkStatus {
+                        line_no,
+                        ..Default::default()
+                    });
+                    continue;
+                }
             };
+            for warning in &source_warnings {
+                eprintln!("warning: {}", warning);
+            }
+            warnings += source_warnings.len();
+            total.add_file(&status);
+        }
 
-            println!(
-                "{}\t{}\t{:.1}%\t{}",
-                source,
-                status.marked,
-                status.marked as f64 / status.line_no as f64 * 100.0,
-                status.line_no
-            );
+        println!(
+            "{} sources, {} errors, {} warnings, {}/{} lines marked ({:.1}%)",
+            total.files,
+            invalid + unreadable,
+            warnings,
+            total.marked,
+            total.line_no,
+            total.percent()
+        );
+        match (invalid, unreadable) {
+            (0, 0) => {}
+            (invalid, 0) => anyhow::bail!("{} spec files are invalid", invalid),
+            (0, unreadable) => anyhow::bail!("{} sources failed to be read", unreadable),
+            (invalid, unreadable) => anyhow::bail!(
+                "{} spec files are invalid, and {} sources failed to be read",
+                invalid,
+                unreadable
+            ),
+        }
+        if let Some(min_percent) = self.min_percent {
+            if total.percent() < min_percent {
+                return Err(anyhow::anyhow!(
+                    "{:.1}% of lines are marked, less than {}%",
+                    total.percent(),
+                    min_percent
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// status of a source with the warnings of its spec file
+fn check_source(
+    source_path: &Path,
+    cache: &LineCountCache,
+) -> anyhow::Result<(FileMarkStatus, Vec<ParseWarning>)> {
+    let spec_file_path = marks::get_spec_file_path(source_path);
+    if !spec_file_path.exists() {
+        let status = FileMarkStatus {
+            line_no: cache.line_count(source_path)?,
+            ..Default::default()
+        };
+        return Ok((status, vec![]));
+    }
+
+    let source = SourceFile::read(source_path)?;
+    let (file_marks, warnings) =
+        marks::parse_spec_file_with_warnings(&spec_file_path, Some(&source))?;
+    Ok((file_marks.status(source.lines.len() as u32), warnings))
+}
+
+#[derive(Args, Debug)]
+struct LsCommand {}
+
+impl LsCommand {
+    fn run(&self) -> anyhow::Result<()> {
+        for store in stores()? {
+            for spec_file_path in marks::list_spec_files(&store)? {
+                let source_path = match marks::source_path_of_spec(&spec_file_path) {
+                    Ok(Some(source_path)) => source_path,
+                    Ok(None) => {
+                        println!("{}\tunknown source", spec_file_path.display());
+                        continue;
+                    }
+                    Err(err) => {
+                        println!("{}\t{}", spec_file_path.display(), err);
+                        continue;
+                    }
+                };
+                if !source_path.is_file() {
+                    println!("{}\tmissing", source_path.display());
+                    continue;
+                }
+
+                let status = match marks::file_status(&source_path) {
+                    Ok(status) => status,
+                    Err(err) => {
+                        println!("{}\t{}", source_path.display(), err);
+                        continue;
+                    }
+                };
+                println!(
+                    "{}\t{}\t{:.1}%\t{}",
+                    source_path.display(),
+                    status.marked,
+                    status.percent(),
+                    status.line_no
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Project-local store of the current directory, if any, and the store in the data directory
+fn stores() -> anyhow::Result<Vec<PathBuf>> {
+    let mut stores = vec![];
+    // store of a source in the current directory
+    let current_dir = env::current_dir()?.join(marks::PROJECT_STORE_DIR);
+    if let Some(store) = marks::find_project_store(current_dir) {
+        stores.push(store);
+    }
+    let spec_file_dir = marks::get_spec_file_dir();
+    if spec_file_dir.is_dir() {
+        stores.push(spec_file_dir);
+    }
+    Ok(stores)
+}
+
+#[derive(Args, Debug)]
+struct GcCommand {
+    /// Remove the listed spec files
+    #[arg(long)]
+    prune: bool,
+}
+
+impl GcCommand {
+    fn run(&self) -> anyhow::Result<()> {
+        for store in stores()? {
+            for spec_file_path in marks::list_spec_files(&store)? {
+                let garbage = match marks::find_garbage(&spec_file_path) {
+                    Ok(Some(garbage)) => garbage,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        eprintln!("{}: {}", spec_file_path.display(), err);
+                        continue;
+                    }
+                };
+                println!("{}\t{}", spec_file_path.display(), garbage);
+                if !self.prune {
+                    continue;
+                }
+
+                marks::remove_spec_file(&spec_file_path)?;
+            }
+        }
+        if !self.prune {
+            eprintln!("dry run: pass --prune to remove the listed spec files");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Args, Debug)]
+struct MvCommand {
+    /// Source to move the marks from, which may not exist anymore
+    #[arg(required_unless_present = "git")]
+    from: Option<String>,
+
+    /// Source to move the marks to
+    #[arg(required_unless_present = "git")]
+    to: Option<String>,
+
+    /// Move marks of all sources in directory FROM to directory TO
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Overwrite existing marks of the destination
+    #[arg(short, long)]
+    force: bool,
+
+    /// Move marks of files renamed since REV, detected by git
+    #[arg(
+        long,
+        value_name = "REV",
+        num_args = 0..=1,
+        default_missing_value = "HEAD",
+        conflicts_with_all = ["from", "to", "recursive"]
+    )]
+    git: Option<String>,
+}
+
+impl MvCommand {
+    fn run(&self) -> anyhow::Result<()> {
+        if let Some(rev) = &self.git {
+            let renames = marks::git_renames(env::current_dir()?, rev)
+                .context("failed to detect renames with git")?;
+            for (from, to) in renames {
+                if !marks::get_spec_file_path(&from).is_file() {
+                    continue;
+                }
+                if let Err(err) = transfer_marks(&from, &to, self.force, false) {
+                    eprintln!("{:#}", err);
+                }
+            }
+            return Ok(());
+        }
+
+        let (Some(from), Some(to)) = (&self.from, &self.to) else {
+            unreachable!("required by clap");
+        };
+        if self.recursive {
+            transfer_directory_marks(Path::new(from), Path::new(to), self.force, false)
+        } else {
+            transfer_marks(Path::new(from), Path::new(to), self.force, false)
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct CpCommand {
+    /// Source to copy the marks from, which may not exist anymore
+    from: String,
+
+    /// Source to copy the marks to
+    to: String,
+
+    /// Copy marks of all sources in directory FROM to directory TO
+    #[arg(short, long)]
+    recursive: bool,
+
+    /// Overwrite existing marks of the destination
+    #[arg(short, long)]
+    force: bool,
+}
+
+impl CpCommand {
+    fn run(&self) -> anyhow::Result<()> {
+        let (from, to) = (Path::new(&self.from), Path::new(&self.to));
+        if self.recursive {
+            transfer_directory_marks(from, to, self.force, true)
+        } else {
+            transfer_marks(from, to, self.force, true)
+        }
+    }
+}
+
+/// Copy the marks of a source to another source, and remove the original unless `keep`
+fn transfer_marks(from: &Path, to: &Path, force: bool, keep: bool) -> anyhow::Result<()> {
+    let context = || format!("{} -> {}", from.display(), to.display());
+
+    let from_spec_file_path = marks::get_spec_file_path(from);
+    let to_spec_file_path = marks::get_spec_file_path(to);
+    if from_spec_file_path == to_spec_file_path {
+        return Ok(());
+    }
+    if !force
+        && to_spec_file_path.is_file()
+        && !marks::parse_spec_file(&to_spec_file_path, None)?.is_empty()
+    {
+        return Err(anyhow::anyhow!(
+            "destination already has marks, use --force to overwrite"
+        ))
+        .with_context(context);
+    }
+
+    marks::copy_marks(from, to).with_context(context)?;
+    if !keep {
+        marks::remove_spec_file(&from_spec_file_path)?;
+    }
+    println!("{}", context());
+    Ok(())
+}
+
+/// Transfer the marks of every source inside directory `from` to the same path inside `to`
+fn transfer_directory_marks(from: &Path, to: &Path, force: bool, keep: bool) -> anyhow::Result<()> {
+    let from = marks::canonical_path(from)?;
+    let to = marks::canonical_path(to)?;
+
+    let mut stores = vec![marks::get_spec_file_dir()];
+    // stores of sources inside the directories
+    for dir in [&from, &to] {
+        if let Some(store) = marks::find_project_store(dir.join(marks::PROJECT_STORE_DIR)) {
+            stores.push(store);
+        }
+    }
+    stores.sort();
+    stores.dedup();
+
+    let mut sources = vec![];
+    for store in stores.into_iter().filter(|store| store.is_dir()) {
+        for spec_file_path in marks::list_spec_files(&store)? {
+            // spec files written by older versions do not record the source
+            if let Ok(Some(source_path)) = marks::source_path_of_spec(&spec_file_path) {
+                if let Ok(relative) = source_path.strip_prefix(&from) {
+                    sources.push((source_path.clone(), to.join(relative)));
+                }
+            }
+        }
+    }
+
+    for (from, to) in sources {
+        if let Err(err) = transfer_marks(&from, &to, force, keep) {
+            eprintln!("{:#}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Spec files in a project-local store move with the project, so only the spec files keyed by
+/// absolute path need to be relocated.
+#[derive(Args, Debug)]
+struct RelocateCommand {
+    /// Previous root of the checkout, which may not exist anymore
+    old_root: String,
+
+    /// Current root of the checkout
+    new_root: String,
+
+    /// Overwrite existing marks of sources in NEW_ROOT
+    #[arg(short, long)]
+    force: bool,
+}
+
+impl RelocateCommand {
+    fn run(&self) -> anyhow::Result<()> {
+        let new_root = Path::new(&self.new_root);
+        if !new_root.is_dir() {
+            return Err(anyhow::anyhow!("{}: not a directory", self.new_root));
+        }
+        transfer_directory_marks(Path::new(&self.old_root), new_root, self.force, false)
+    }
+}
+
+#[derive(Args, Debug)]
+struct InitCommand {
+    /// Root directory of the project
+    #[arg(default_value = ".")]
+    root: String,
+}
+
+impl InitCommand {
+    fn run(&self) -> anyhow::Result<()> {
+        let store = PathBuf::from(&self.root).join(marks::PROJECT_STORE_DIR);
+        fs::create_dir_all(&store)?;
+        println!("{}", store.display());
+        Ok(())
+    }
+}
+
+/// Print the spec of category, or replace the spec of category in the marks of source with it
+fn output_spec(
+    source: &SourceFile,
+    category: Category,
+    spec: FileMarkSpec,
+    write: bool,
+) -> anyhow::Result<()> {
+    let spec_file_path = marks::get_spec_file_path(&source.path);
+    let spec_file_dir = spec_file_path
+        .parent()
+        .context("failed to get parent directory")?;
+
+    if !write {
+        let mut file_marks = FileMarks::default();
+        file_marks.set_spec(category, spec);
+        print!("{}", marks::format_spec(&file_marks, Some(source))?);
+        return Ok(());
+    }
+
+    let mut file_marks = marks::read_marks(&spec_file_path, Some(source))?;
+    file_marks.set_spec(category, spec);
+
+    fs::create_dir_all(spec_file_dir)?;
+    let tmp = tempfile::NamedTempFile::new_in(spec_file_dir)?;
+    marks::write_spec_file(tmp.path(), &file_marks, Some(source))?;
+
+    fs::rename(tmp.path(), &spec_file_path)?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+enum SetOperation {
+    /// Lines marked in any spec
+    Union,
+    /// Lines marked in every spec
+    Intersection,
+    /// Lines marked in source but not in the other specs
+    Difference,
+}
+
+#[derive(Args, Debug)]
+struct CombineCommand {
+    operation: SetOperation,
+
+    source: String,
+
+    /// Spec files to combine with the spec of source, e.g. shared by other reviewers
+    #[arg(required = true)]
+    specs: Vec<String>,
+
+    /// Category of marks to combine
+    #[arg(long, default_value_t = Category::Read)]
+    category: Category,
+
+    /// Replace the spec of source instead of printing the result
+    #[arg(long, default_value_t = false)]
+    write: bool,
+}
+
+impl CombineCommand {
+    fn run(&self) -> anyhow::Result<()> {
+        let spec_file_path = marks::get_spec_file_path(&self.source);
+
+        let source = SourceFile::read(&self.source)?;
+        let file_marks = marks::read_marks(&spec_file_path, Some(&source))?;
+        let mut spec = file_marks.spec(self.category).cloned().unwrap_or_default();
+        for other in &self.specs {
+            let other = marks::parse_spec_file(other, Some(&source))
+                .with_context(|| format!("failed to parse spec file: {}", other))?;
+            let other = other.spec(self.category).cloned().unwrap_or_default();
+            spec = match self.operation {
+                SetOperation::Union => spec.union(&other),
+                SetOperation::Intersection => spec.intersection(&other),
+                SetOperation::Difference => spec.difference(&other),
+            };
+        }
+
+        output_spec(&source, self.category, spec, self.write)
+    }
+}
+
+#[derive(Args, Debug)]
+struct InvertCommand {
+    source: String,
+
+    /// Category of marks to invert
+    #[arg(long, default_value_t = Category::Read)]
+    category: Category,
+
+    /// Replace the spec of source instead of printing the result
+    #[arg(long, default_value_t = false)]
+    write: bool,
+}
+
+impl InvertCommand {
+    fn run(&self) -> anyhow::Result<()> {
+        let spec_file_path = marks::get_spec_file_path(&self.source);
+
+        let source = SourceFile::read(&self.source)?;
+        let file_marks = marks::read_marks(&spec_file_path, Some(&source))?;
+        let spec = file_marks
+            .spec(self.category)
+            .cloned()
+            .unwrap_or_default()
+            .complement(source.lines.len() as u32);
+
+        output_spec(&source, self.category, spec, self.write)
+    }
+}
+
+#[derive(Args, Debug)]
+struct NotesCommand {
+    sources: Vec<String>,
+}
+
+impl NotesCommand {
+    fn run(&self) -> anyhow::Result<()> {
+        for source_path in &self.sources {
+            let spec_file_path = marks::get_spec_file_path(source_path);
+
+            let source = SourceFile::read(source_path)?;
+            let file_marks = marks::read_marks(&spec_file_path, Some(&source))?;
+            for note in file_marks.notes() {
+                // 1-index, inclusive
+                let (first, last) = (note.range.start + 1, note.range.end);
+                if first == last {
+                    println!("{}:{}: {}", source_path, first, note.text);
+                } else {
+                    println!("{}:{}-{}: {}", source_path, first, last, note.text);
+                }
+            }
         }
         Ok(())
     }
@@ -584,7 +1680,7 @@ struct DebugCommand {
 
     /// Print spec path
     #[arg(long, default_value_t = false)]
-    path: bool
+    path: bool,
 }
 
 impl DebugCommand {
@@ -626,6 +1722,16 @@ fn main() -> Result<(), Box<dyn error::Error>> {
         Commands::Edit(edit) => edit.run()?,
         Commands::View(view) => view.run()?,
         Commands::Status(status) => status.run()?,
+        Commands::Check(check) => check.run()?,
+        Commands::Ls(ls) => ls.run()?,
+        Commands::Gc(gc) => gc.run()?,
+        Commands::Mv(mv) => mv.run()?,
+        Commands::Cp(cp) => cp.run()?,
+        Commands::Relocate(relocate) => relocate.run()?,
+        Commands::Init(init) => init.run()?,
+        Commands::Combine(combine) => combine.run()?,
+        Commands::Invert(invert) => invert.run()?,
+        Commands::Notes(notes) => notes.run()?,
         Commands::Debug(debug) => debug.run()?,
     }
     Ok(())